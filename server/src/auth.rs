@@ -6,7 +6,9 @@ use axum::{
 use headers::{authorization::Bearer, Authorization};
 use http::request::Parts;
 
-pub struct Auth(String);
+/// The access token of the request, one of the comma separated `AUTH_SECRET_KEY`. Tokens
+/// only tell users apart for budgets, every token sees the whole history.
+pub struct Auth(pub String);
 
 #[async_trait::async_trait]
 impl<S> FromRequestParts<S> for Auth
@@ -16,8 +18,8 @@ where
     type Rejection = StatusCode;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        let key = shared::get_env(shared::gpt::AUTH_SECRET_KEY);
-        if key.is_empty() {
+        let keys = shared::get_env_list(shared::gpt::AUTH_SECRET_KEY);
+        if keys.is_empty() {
             return Ok(Self("".to_owned()));
         }
         // Extract the token from the authorization header
@@ -25,7 +27,7 @@ where
             .extract::<TypedHeader<Authorization<Bearer>>>()
             .await
             .map_err(|_| StatusCode::UNAUTHORIZED)?;
-        if bearer.token() != key {
+        if !keys.iter().any(|x| x == bearer.token()) {
             return Err(StatusCode::UNAUTHORIZED);
         }
         Ok(Self(bearer.token().to_owned()))
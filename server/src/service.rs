@@ -1,6 +1,7 @@
 use crate::auth::*;
 use axum::{
     error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
     response::IntoResponse,
     routing::{get_service, post},
     Json, Router,
@@ -62,10 +63,30 @@ pub async fn run() -> shared::Result<()> {
     let app = Router::new()
         .nest_service("/", get_service(ServeDir::new("asset")))
         .route("/api/session", post(get_session))
+        .route("/api/models", post(models))
         .route(
             "/api/chat-process",
             post(chat_process).route_layer(rate_limit_layer.clone()),
         )
+        .route(
+            "/api/chat-regenerate",
+            post(chat_regenerate).route_layer(rate_limit_layer.clone()),
+        )
+        .route("/api/chat-abort", post(chat_abort))
+        .route("/api/message-children", post(message_children))
+        .route("/api/message-siblings", post(message_siblings))
+        .route("/api/message-path", post(message_path))
+        .route("/api/message-delete", post(message_delete))
+        .route("/api/conversations", post(conversations))
+        .route("/api/conversation", post(conversation))
+        .route("/api/conversation-rename", post(conversation_rename))
+        .route("/api/conversation-select", post(conversation_select))
+        .route("/api/conversation-delete", post(conversation_delete))
+        .route("/api/export", post(export))
+        .route(
+            "/api/import",
+            post(import).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)),
+        )
         .route("/api/config", post(config))
         .route("/api/verify", post(verify))
         .layer(TraceLayer::new_for_http())
@@ -95,13 +116,28 @@ async fn get_session() -> Result<Json<RespValue>, String> {
     Ok(Json(gpt::get_session()))
 }
 
-type Rx = std::sync::Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<ChatMsgWithRx>>>;
+async fn models(_: Auth) -> Result<Json<RespValue>, String> {
+    Ok(Json(catalog::models().await.map_err(|x| x.to_string())?))
+}
+
+/// ChatGPT data exports easily exceed the default 2MB.
+const IMPORT_BODY_LIMIT: usize = 256 * 1024 * 1024;
+
+/// Events buffered per chat before upstream is made to wait for a slow client.
+const CHANNEL_SIZE: usize = 32;
+
+type Rx = std::sync::Arc<tokio::sync::Mutex<mpsc::Receiver<ChatMsgWithRx>>>;
 #[derive(Serialize, Default)]
 struct ChatMsgWithRx {
     #[serde(flatten, skip_serializing_if = "Option::is_none")]
     msg: Option<ChatMessage>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    /// Set with `error` when clients can act on it, e.g. `budget_exceeded`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<shared::serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     status: Option<String>,
     #[serde(skip_serializing)]
@@ -129,38 +165,83 @@ impl ChatMsgWithRx {
         }
     }
 
-    fn new_err<T: ToString>(err: T) -> Self {
+    fn new_err(err: shared::anyhow::Error) -> Self {
+        let structured = err.downcast_ref::<StructuredError>();
         Self {
             error: Some(err.to_string()),
+            code: structured.map(|x| x.code),
+            details: structured.map(|x| x.details.clone()),
             ..Default::default()
         }
     }
 
-    fn done() -> Self {
+    /// The final event carries the whole answer with its metadata.
+    fn done(msg: ChatMessage) -> Self {
         Self {
+            msg: Some(msg),
             status: Some("Done".to_owned()),
             ..Default::default()
         }
     }
+
+    fn cancelled(msg: ChatMessage) -> Self {
+        Self {
+            msg: Some(msg),
+            status: Some("Cancelled".to_owned()),
+            ..Default::default()
+        }
+    }
 }
 
-async fn chat_process(_: Auth, Json(payload): Json<gpt::RequestOptions>) -> impl IntoResponse {
-    let (tx, rx) = mpsc::unbounded_channel();
+async fn chat_process(
+    Auth(token): Auth,
+    Json(mut payload): Json<gpt::RequestOptions>,
+) -> impl IntoResponse {
+    payload.access_token = token;
+    stream_chat(move |tx| gpt::chat_process(payload, Some(on_progress(tx))))
+}
+
+async fn chat_regenerate(
+    Auth(token): Auth,
+    Json(mut payload): Json<gpt::RegenerateOptions>,
+) -> impl IntoResponse {
+    payload.options.access_token = token;
+    stream_chat(move |tx| gpt::chat_regenerate(payload, Some(on_progress(tx))))
+}
+
+/// Fails once the receiver is dropped with the response stream, i.e. the client went away.
+fn on_progress(
+    tx: mpsc::Sender<ChatMsgWithRx>,
+) -> impl Fn(ChatMessage) -> future::BoxFuture<'static, shared::Result<()>> {
+    move |msg| {
+        let tx = tx.clone();
+        async move {
+            tx.send(ChatMsgWithRx::new_msg(msg))
+                .await
+                .map_err(|_| anyhow::anyhow!("Client disconnected"))
+        }
+        .boxed()
+    }
+}
+
+/// Run a chat in the background and stream its progress as NDJSON.
+fn stream_chat<F, Fut>(run: F) -> impl IntoResponse
+where
+    F: FnOnce(mpsc::Sender<ChatMsgWithRx>) -> Fut,
+    Fut: Future<Output = shared::Result<RespData<ChatMessage>>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+    let chat = run(tx.clone());
     tokio::spawn(async move {
-        let tx_cloned = tx.clone();
-        match gpt::chat_process(
-            payload,
-            Some(move |msg| {
-                tx.send(ChatMsgWithRx::new_msg(msg)).ok();
-            }),
-        )
-        .await
-        {
+        match chat.await {
             Err(err) => {
-                tx_cloned.send(ChatMsgWithRx::new_err(err)).ok();
+                tx.send(ChatMsgWithRx::new_err(err)).await.ok();
             }
-            _ => {
-                tx_cloned.send(ChatMsgWithRx::done()).ok();
+            Ok(res) if res.data.finish_reason() == Some(gpt::CANCELLED) => {
+                tx.send(ChatMsgWithRx::cancelled(res.data)).await.ok();
+            }
+            Ok(res) => {
+                tx.send(ChatMsgWithRx::done(res.data)).await.ok();
             }
         }
     });
@@ -176,6 +257,110 @@ async fn chat_process(_: Auth, Json(payload): Json<gpt::RequestOptions>) -> impl
     StreamBodyAs::json_nl(resp_stream)
 }
 
+async fn chat_abort(_: Auth, Json(payload): Json<gpt::IdBody>) -> Json<RespValue> {
+    Json(gpt::chat_abort(payload))
+}
+
+async fn message_children(
+    _: Auth,
+    Json(payload): Json<gpt::IdBody>,
+) -> Result<Json<RespValue>, String> {
+    Ok(Json(
+        history::message_children(payload).map_err(|x| x.to_string())?,
+    ))
+}
+
+async fn message_siblings(
+    _: Auth,
+    Json(payload): Json<gpt::IdBody>,
+) -> Result<Json<RespValue>, String> {
+    Ok(Json(
+        history::message_siblings(payload).map_err(|x| x.to_string())?,
+    ))
+}
+
+async fn message_path(
+    _: Auth,
+    Json(payload): Json<gpt::IdBody>,
+) -> Result<Json<RespValue>, String> {
+    Ok(Json(
+        history::message_path(payload).map_err(|x| x.to_string())?,
+    ))
+}
+
+async fn message_delete(
+    _: Auth,
+    Json(payload): Json<gpt::IdBody>,
+) -> Result<Json<RespValue>, String> {
+    Ok(Json(
+        history::message_delete(payload).map_err(|x| x.to_string())?,
+    ))
+}
+
+async fn conversations(_: Auth) -> Result<Json<RespValue>, String> {
+    Ok(Json(history::conversations().map_err(|x| x.to_string())?))
+}
+
+async fn conversation(
+    _: Auth,
+    Json(payload): Json<gpt::IdBody>,
+) -> Result<Json<RespValue>, String> {
+    Ok(Json(
+        history::conversation(payload).map_err(|x| x.to_string())?,
+    ))
+}
+
+async fn conversation_rename(
+    _: Auth,
+    Json(payload): Json<history::RenameBody>,
+) -> Result<Json<RespValue>, String> {
+    Ok(Json(
+        history::conversation_rename(payload).map_err(|x| x.to_string())?,
+    ))
+}
+
+async fn conversation_select(
+    _: Auth,
+    Json(payload): Json<gpt::IdBody>,
+) -> Result<Json<RespValue>, String> {
+    Ok(Json(
+        history::conversation_select(payload).map_err(|x| x.to_string())?,
+    ))
+}
+
+async fn conversation_delete(
+    _: Auth,
+    Json(payload): Json<gpt::IdBody>,
+) -> Result<Json<RespValue>, String> {
+    Ok(Json(
+        history::conversation_delete(payload).map_err(|x| x.to_string())?,
+    ))
+}
+
+async fn export(
+    _: Auth,
+    Json(payload): Json<export::ExportBody>,
+) -> Result<impl IntoResponse, String> {
+    let export = export::export(payload).map_err(|x| x.to_string())?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, export.content_type.to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", export.filename),
+            ),
+        ],
+        export.content,
+    ))
+}
+
+async fn import(
+    _: Auth,
+    Json(payload): Json<import::ImportBody>,
+) -> Result<Json<RespValue>, String> {
+    Ok(Json(import::import(payload).map_err(|x| x.to_string())?))
+}
+
 async fn config(_: Auth, Json(payload): Json<gpt::DateRange>) -> Result<Json<RespValue>, String> {
     Ok(Json(
         gpt::chat_config(payload, true).await.map_err(|x| x.to_string())?,
@@ -0,0 +1,37 @@
+use futures::future::AbortHandle;
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, sync::Mutex};
+
+/// In-flight answers by assistant message id.
+static RUNNING: Lazy<Mutex<HashMap<String, AbortHandle>>> = Lazy::new(Default::default);
+
+/// Keeps an answer abortable until dropped.
+pub struct Running(String);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.0);
+    }
+}
+
+pub fn register(id: &str, handle: AbortHandle) -> Running {
+    RUNNING.lock().unwrap().insert(id.to_owned(), handle);
+    Running(id.to_owned())
+}
+
+/// Stop the answer `id`, false if it is not running (any more).
+pub fn abort(id: &str) -> bool {
+    match RUNNING.lock().unwrap().get(id) {
+        Some(handle) => {
+            handle.abort();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Ids of the answers in flight.
+#[cfg(test)]
+pub(crate) fn running() -> Vec<String> {
+    RUNNING.lock().unwrap().keys().cloned().collect()
+}
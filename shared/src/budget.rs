@@ -0,0 +1,238 @@
+use crate::{
+    format_date, get_env, get_env_list, now_ms,
+    provider::keys,
+    usage::{self, Totals},
+    Result, StructuredError,
+};
+use serde_json::json;
+
+pub const BUDGET_EXCEEDED: &str = "budget_exceeded";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Period {
+    Daily,
+    Monthly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Limit {
+    Usd(f64),
+    Tokens(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Budget {
+    period: Period,
+    limit: Limit,
+}
+
+impl std::fmt::Display for Budget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let period = match self.period {
+            Period::Daily => "daily",
+            Period::Monthly => "monthly",
+        };
+        match self.limit {
+            Limit::Usd(x) => write!(f, "{period} budget of ${x:.2}"),
+            Limit::Tokens(x) => write!(f, "{period} budget of {x} tokens"),
+        }
+    }
+}
+
+impl Budget {
+    /// `daily:$5` or `monthly:2000000`, a bare number counts tokens.
+    fn parse(spec: &str) -> Option<Self> {
+        let (period, limit) = spec.split_once(':')?;
+        let period = match period.trim() {
+            "daily" => Period::Daily,
+            "monthly" => Period::Monthly,
+            _ => return None,
+        };
+        let limit = limit.trim();
+        let limit = match limit.strip_prefix('$') {
+            Some(usd) => Limit::Usd(usd.parse().ok()?),
+            None => Limit::Tokens(limit.parse().ok()?),
+        };
+        Some(Self { period, limit })
+    }
+
+    /// Today `YYYY-MM-DD` or this month `YYYY-MM`, as running totals are kept.
+    fn current_period(&self) -> String {
+        let today = format_date(now_ms());
+        match self.period {
+            Period::Daily => today,
+            Period::Monthly => today[..7].to_owned(),
+        }
+    }
+
+    /// How much of the budget is used, 1.0 and more when exceeded.
+    fn used(&self, totals: &Totals) -> f64 {
+        match self.limit {
+            Limit::Usd(x) => totals.cost / x,
+            Limit::Tokens(x) => (totals.prompt_tokens + totals.completion_tokens) as f64 / x as f64,
+        }
+    }
+}
+
+fn budgets(key: &str) -> Vec<Budget> {
+    get_env_list(key)
+        .iter()
+        .filter_map(|x| {
+            let budget = Budget::parse(x);
+            if budget.is_none() {
+                log::warn!("Invalid {key} entry: {x}");
+            }
+            budget
+        })
+        .collect()
+}
+
+/// Fraction of a budget at which clients are warned, `BUDGET_WARN_PERCENT` or 80%.
+fn warn_ratio() -> f64 {
+    get_env("BUDGET_WARN_PERCENT").parse().unwrap_or(80.0) / 100.0
+}
+
+/// Usage of every budget of `who`, an account or a user as `scope` says.
+fn usage_of(budgets: &[Budget], scope: &str, who: &str) -> Result<Vec<(Budget, f64)>> {
+    budgets
+        .iter()
+        .map(|budget| {
+            let totals = usage::totals(&budget.current_period(), scope, who)?;
+            Ok((*budget, budget.used(&totals)))
+        })
+        .collect()
+}
+
+fn exceeded(scope: &str, who: &str, budget: &Budget, used: f64) -> anyhow::Error {
+    StructuredError {
+        code: BUDGET_EXCEEDED,
+        message: format!("The {budget} of {who} is used up"),
+        details: json!({
+            "scope": scope,
+            "budget": budget.to_string(),
+            "usedPercent": (used * 100.0).round(),
+        }),
+    }
+    .into()
+}
+
+/// Pooled keys over one of their `KEY_BUDGET` limits, which must not be used any more.
+pub fn exhausted_keys() -> Result<Vec<String>> {
+    let budgets = budgets("KEY_BUDGET");
+    if budgets.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut exhausted = vec![];
+    for key in keys::get_keys() {
+        let usage = usage_of(&budgets, "account", &keys::account_id(&key))?;
+        if usage.iter().any(|(_, used)| *used >= 1.0) {
+            exhausted.push(key);
+        }
+    }
+    Ok(exhausted)
+}
+
+/// Enforce `TOKEN_BUDGET` of the access token and `KEY_BUDGET` of the pooled keys before
+/// anything is sent upstream. Budgets close to their limit come back as warnings.
+pub fn check(access_token: &str) -> Result<Vec<String>> {
+    let warn = warn_ratio();
+    let mut warnings = vec![];
+    if !access_token.is_empty() {
+        let user = usage::user_id(access_token);
+        for (budget, used) in usage_of(&budgets("TOKEN_BUDGET"), "user", &user)? {
+            if used >= 1.0 {
+                return Err(exceeded("accessToken", "this access token", &budget, used));
+            }
+            if used >= warn {
+                warnings.push(format!(
+                    "{:.0}% of the {budget} of this access token is used",
+                    used * 100.0
+                ));
+            }
+        }
+    }
+    let budgets = budgets("KEY_BUDGET");
+    let keys = keys::get_keys();
+    if budgets.is_empty() || keys.is_empty() {
+        return Ok(warnings);
+    }
+    // the fullest budget of the key that has the most left
+    let mut best: Option<(Budget, f64)> = None;
+    for key in &keys {
+        let fullest = usage_of(&budgets, "account", &keys::account_id(key))?
+            .into_iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some(fullest) = fullest {
+            if best.map(|x| fullest.1 < x.1).unwrap_or(true) {
+                best = Some(fullest);
+            }
+        }
+    }
+    match best {
+        Some((budget, used)) if used >= 1.0 => {
+            return Err(exceeded("apiKey", "every API key", &budget, used));
+        }
+        Some((budget, used)) if used >= warn => {
+            warnings.push(format!(
+                "{:.0}% of the {budget} of the API keys is used",
+                used * 100.0
+            ));
+        }
+        _ => {}
+    }
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Budget::parse("daily:$5"),
+            Some(Budget {
+                period: Period::Daily,
+                limit: Limit::Usd(5.0)
+            })
+        );
+        assert_eq!(
+            Budget::parse("monthly: 20000"),
+            Some(Budget {
+                period: Period::Monthly,
+                limit: Limit::Tokens(20000)
+            })
+        );
+        assert_eq!(Budget::parse("weekly:$5"), None);
+    }
+
+    fn spend(access_token: &str, tokens: usize) {
+        let msg = crate::gpt::ChatMessage {
+            model: Some("test-model".to_owned()),
+            metadata: Some(crate::gpt::Metadata {
+                prompt_tokens: tokens,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        usage::record(&msg, "test", &usage::user_id(access_token)).unwrap();
+    }
+
+    #[test]
+    fn test_check() {
+        std::env::set_var("TOKEN_BUDGET", "daily:1000");
+        let token = format!("tok-{}-end1", uuid::Uuid::new_v4());
+        // same prefix and suffix, still a budget of its own
+        let other = format!("tok-{}-end1", uuid::Uuid::new_v4());
+        assert!(check(&token).unwrap().is_empty());
+        spend(&token, 900);
+        assert_eq!(check(&token).unwrap().len(), 1);
+        assert!(check(&other).unwrap().is_empty());
+        spend(&token, 100);
+        let err = check(&token).unwrap_err();
+        let err = err.downcast_ref::<StructuredError>().unwrap();
+        assert_eq!(err.code, BUDGET_EXCEEDED);
+        assert_eq!(err.details["scope"], "accessToken");
+        assert!(check(&other).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+use crate::{get_env, provider::Provider, resp_data, store, RespData, Result};
+use async_openai::types::ChatCompletionRequestMessage;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::{collections::HashMap, path::PathBuf, sync::Mutex, time::SystemTime};
+use tiktoken_rs::{cl100k_base, p50k_base, r50k_base, CoreBPE};
+
+/// What we know about a model, every field optional so entries can be partial.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelInfo {
+    /// Tokens of prompt and answer together.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<usize>,
+    /// Tokens kept free for the answer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output: Option<usize>,
+    /// tiktoken encoding, `cl100k_base`, `p50k_base` or `r50k_base`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokenizer: Option<String>,
+    /// USD per 1K prompt tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_price: Option<f64>,
+    /// USD per 1K completion tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_price: Option<f64>,
+    /// Function calling, needed by tools.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub functions: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub streaming: Option<bool>,
+}
+
+impl ModelInfo {
+    /// Fields of `self`, those it leaves unset taken from `other`.
+    fn or(self, other: ModelInfo) -> ModelInfo {
+        ModelInfo {
+            context_window: self.context_window.or(other.context_window),
+            max_output: self.max_output.or(other.max_output),
+            tokenizer: self.tokenizer.or(other.tokenizer),
+            prompt_price: self.prompt_price.or(other.prompt_price),
+            completion_price: self.completion_price.or(other.completion_price),
+            functions: self.functions.or(other.functions),
+            streaming: self.streaming.or(other.streaming),
+        }
+    }
+
+    /// Unknown models are assumed to support everything.
+    pub fn supports_functions(&self) -> bool {
+        self.functions.unwrap_or(true)
+    }
+
+    pub fn supports_streaming(&self) -> bool {
+        self.streaming.unwrap_or(true)
+    }
+}
+
+/// Name prefix, context window, max output, prompt and completion price.
+// https://platform.openai.com/docs/models
+const BUILT_IN: &[(&str, usize, usize, f64, f64)] = &[
+    ("gpt-3.5-turbo", 4096, 1000, 0.0015, 0.002),
+    ("gpt-3.5-turbo-16k", 16384, 1000, 0.003, 0.004),
+    ("gpt-4", 8192, 2048, 0.03, 0.06),
+    ("gpt-4-32k", 32768, 8192, 0.06, 0.12),
+];
+
+/// Snapshots from before function calling.
+const NO_FUNCTIONS: &[&str] = &["gpt-3.5-turbo-0301", "gpt-4-0314", "gpt-4-32k-0314"];
+
+fn built_in() -> Entries {
+    let models = BUILT_IN
+        .iter()
+        .map(|(prefix, context, output, prompt, completion)| {
+            let info = ModelInfo {
+                context_window: Some(*context),
+                max_output: Some(*output),
+                tokenizer: Some("cl100k_base".to_owned()),
+                prompt_price: Some(*prompt),
+                completion_price: Some(*completion),
+                functions: Some(true),
+                streaming: Some(true),
+            };
+            (prefix.to_string(), info)
+        });
+    let snapshots = NO_FUNCTIONS.iter().map(|prefix| {
+        let info = ModelInfo {
+            functions: Some(false),
+            ..Default::default()
+        };
+        (prefix.to_string(), info)
+    });
+    models.chain(snapshots).collect()
+}
+
+/// `MODEL_CATALOG`, or `models.json` next to the store.
+fn catalog_path() -> PathBuf {
+    match get_env("MODEL_CATALOG") {
+        path if path.is_empty() => store::data_dir().join("models.json"),
+        path => PathBuf::from(path),
+    }
+}
+
+/// Name prefixes and what they say about the models they match.
+type Entries = Vec<(String, ModelInfo)>;
+
+/// The user's catalog with the time of the file it was read from, re-read when that changes.
+static USER: Lazy<Mutex<(Option<SystemTime>, Entries)>> = Lazy::new(Default::default);
+
+fn user_catalog() -> Entries {
+    let path = catalog_path();
+    let modified = std::fs::metadata(&path).and_then(|x| x.modified()).ok();
+    let mut guard = USER.lock().unwrap();
+    if guard.0 != modified {
+        let entries: HashMap<String, ModelInfo> = match modified {
+            None => Default::default(),
+            Some(_) => std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|x| Ok(serde_json::from_slice(&x)?))
+                .unwrap_or_else(|err| {
+                    log::warn!("Invalid model catalog {}: {err}", path.display());
+                    Default::default()
+                }),
+        };
+        *guard = (modified, entries.into_iter().collect());
+    }
+    guard.1.clone()
+}
+
+/// Everything known about `model`, merged from the entries whose name prefixes it. Each
+/// field comes from the first entry that sets it, in this order:
+/// 1. the longer prefix, as it is more specific
+/// 2. for prefixes of the same length, the user's catalog over the built-in one
+pub fn get(model: &str) -> ModelInfo {
+    let user = user_catalog().into_iter().map(|(k, v)| (k, true, v));
+    let mut entries: Vec<(String, bool, ModelInfo)> = built_in()
+        .into_iter()
+        .map(|(k, v)| (k, false, v))
+        .chain(user)
+        .filter(|(k, _, _)| model.starts_with(k.as_str()))
+        .collect();
+    entries
+        .sort_by(|(a, a_user, _), (b, b_user, _)| b.len().cmp(&a.len()).then(b_user.cmp(a_user)));
+    entries
+        .into_iter()
+        .fold(ModelInfo::default(), |acc, (_, _, v)| acc.or(v))
+}
+
+/// Context window of `model` from the catalog, or what the provider thinks it is.
+pub fn context_window(provider: &dyn Provider, model: &str) -> usize {
+    get(model)
+        .context_window
+        .unwrap_or_else(|| provider.context_size(model))
+}
+
+static BPES: Lazy<Mutex<HashMap<String, Option<&'static CoreBPE>>>> = Lazy::new(Default::default);
+
+fn bpe(tokenizer: &str) -> Option<&'static CoreBPE> {
+    let mut bpes = BPES.lock().unwrap();
+    *bpes.entry(tokenizer.to_owned()).or_insert_with(|| {
+        let bpe = match tokenizer {
+            "cl100k_base" => cl100k_base(),
+            "p50k_base" => p50k_base(),
+            "r50k_base" => r50k_base(),
+            _ => {
+                log::warn!("Unknown tokenizer {tokenizer}");
+                return None;
+            }
+        };
+        // loaded once per tokenizer and kept for good
+        bpe.ok().map(|x| &*Box::leak(Box::new(x)))
+    })
+}
+
+/// Tokens of `messages` with the catalog's tokenizer of `model`, or as the provider counts.
+pub fn num_tokens(
+    provider: &dyn Provider,
+    model: &str,
+    messages: &[ChatCompletionRequestMessage],
+) -> Result<usize> {
+    match get(model).tokenizer.as_deref().and_then(bpe) {
+        Some(bpe) => Ok(crate::provider::count_messages(bpe, messages)),
+        None => provider.num_tokens(model, messages),
+    }
+}
+
+/// Models of the provider and those clients may pick, with what the catalog knows of them.
+pub async fn models() -> Result<RespData<Value>> {
+    let provider = crate::provider::get_provider()?;
+    let live = provider.list_models().await.unwrap_or_else(|err| {
+        log::warn!("List models of {}: {err}", provider.name());
+        vec![]
+    });
+    let allowed = crate::gpt::allowed_models();
+    let mut ids: Vec<&String> = allowed.iter().chain(live.iter()).collect();
+    ids.sort();
+    ids.dedup();
+    let models: Vec<Value> = ids
+        .into_iter()
+        .map(|id| {
+            let mut model = json!(get(id));
+            model["id"] = json!(id);
+            model["live"] = json!(live.contains(id));
+            model["allowed"] = json!(allowed.contains(id));
+            model
+        })
+        .collect();
+    Ok(resp_data(json!(models)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        let path = std::env::temp_dir().join(format!("models-{}.json", uuid::Uuid::new_v4()));
+        // models other tests don't look at, they may run meanwhile
+        std::fs::write(
+            &path,
+            r#"{
+                "gpt-3.5": {"contextWindow": 1, "promptPrice": 9.0},
+                "gpt-3.5-turbo-16k": {"contextWindow": 20000},
+                "gpt-3.5-turbo-16k-0301": {"functions": false},
+                "test-llama": {"functions": false}
+            }"#,
+        )
+        .unwrap();
+        std::env::set_var("MODEL_CATALOG", &path);
+        // same prefix, the user's entry wins field by field
+        let info = get("gpt-3.5-turbo-16k-0613");
+        assert_eq!(info.context_window, Some(20000));
+        assert_eq!(info.max_output, Some(1000));
+        // a longer built-in prefix beats a shorter user one
+        assert_eq!(info.prompt_price, Some(0.003));
+        assert!(info.supports_functions());
+        // and a longer user prefix beats a built-in one
+        assert!(!get("gpt-3.5-turbo-16k-0301").supports_functions());
+        assert_eq!(get("gpt-3.5-instruct").prompt_price, Some(9.0));
+        assert!(!get("test-llama2").supports_functions());
+        std::env::set_var("MODEL_CATALOG", path.with_extension("missing"));
+        std::fs::remove_file(&path).ok();
+        let info = get("gpt-4-0314");
+        assert_eq!(info.context_window, Some(8192));
+        assert_eq!(info.max_output, Some(2048));
+        assert!(!info.supports_functions());
+        let info = get("gpt-4-32k-0613");
+        assert_eq!(info.context_window, Some(32768));
+        assert_eq!(info.prompt_price, Some(0.06));
+        assert!(info.supports_functions());
+        assert_eq!(get("llama2"), ModelInfo::default());
+    }
+}
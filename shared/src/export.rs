@@ -0,0 +1,204 @@
+use crate::{
+    format_datetime,
+    gpt::ChatMessage,
+    history::{self, Conversation},
+    Result,
+};
+use anyhow::bail;
+use async_openai::types::Role;
+use serde_json::json;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Markdown,
+    Json,
+    Html,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Markdown => "md",
+            Self::Json => "json",
+            Self::Html => "html",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Markdown => "text/markdown; charset=utf-8",
+            Self::Json => "application/json",
+            Self::Html => "text/html; charset=utf-8",
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct ExportBody {
+    /// Conversation id.
+    pub id: String,
+    /// Last message of the branch to export, the conversation's latest message if not set.
+    #[serde(default, rename = "leafId")]
+    pub leaf_id: Option<String>,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+pub struct Export {
+    pub filename: String,
+    pub content_type: &'static str,
+    pub content: String,
+}
+
+/// Render one branch of a conversation, from its first question down to the leaf.
+pub fn export(b: ExportBody) -> Result<Export> {
+    let conversation = history::get_conversation(&b.id)?;
+    let leaf = b.leaf_id.as_deref().unwrap_or(&conversation.leaf_id);
+    let messages = history::path(leaf)?;
+    if messages
+        .iter()
+        .any(|x| x.last_context.conversation_id.as_deref() != Some(b.id.as_str()))
+    {
+        bail!("Message {leaf} is not in conversation {}", b.id);
+    }
+    let content = match b.format {
+        ExportFormat::Markdown => to_markdown(&conversation, &messages),
+        ExportFormat::Json => to_json(&conversation, &messages)?,
+        ExportFormat::Html => to_html(&conversation, &messages),
+    };
+    Ok(Export {
+        filename: format!("{}.{}", filename(&conversation), b.format.extension()),
+        content_type: b.format.content_type(),
+        content,
+    })
+}
+
+fn filename(conversation: &Conversation) -> String {
+    let name: String = conversation
+        .title
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let name = name
+        .split('-')
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    if name.is_empty() {
+        "conversation".to_owned()
+    } else {
+        name
+    }
+}
+
+fn title(conversation: &Conversation) -> &str {
+    if conversation.title.is_empty() {
+        "Conversation"
+    } else {
+        &conversation.title
+    }
+}
+
+fn role(msg: &ChatMessage) -> &'static str {
+    match msg.role {
+        _ if msg.name.is_some() => "function",
+        Some(Role::Assistant) => "assistant",
+        Some(Role::System) => "system",
+        _ => "user",
+    }
+}
+
+// tool calls and results are left out of the readable formats, like in the prompt
+fn to_markdown(conversation: &Conversation, messages: &[ChatMessage]) -> String {
+    let mut out = format!(
+        "# {}\n\n_{}_\n",
+        title(conversation),
+        format_datetime(conversation.created_at)
+    );
+    for msg in messages.iter().filter(|x| !x.is_tool_step()) {
+        let who = match msg.role {
+            Some(Role::Assistant) => "Assistant",
+            _ => "User",
+        };
+        out.push_str(&format!("\n**{who}**:\n\n{}\n", msg.text.trim_end()));
+    }
+    out
+}
+
+fn to_json(conversation: &Conversation, messages: &[ChatMessage]) -> Result<String> {
+    let messages: Vec<_> = messages
+        .iter()
+        .map(|x| {
+            json!({
+                "id": x.id,
+                "parentMessageId": x.last_context.parent_message_id,
+                "role": role(x),
+                "name": x.name,
+                "functionCall": x.function_call,
+                "text": x.text,
+                "createdAt": x.created_at,
+                "model": x.model,
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&json!({
+        "id": conversation.id,
+        "title": conversation.title,
+        "createdAt": conversation.created_at,
+        "updatedAt": conversation.updated_at,
+        "messages": messages,
+    }))?)
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+const HTML_STYLE: &str = "body{font-family:system-ui,sans-serif;max-width:48rem;margin:2rem auto;\
+padding:0 1rem;color:#24292f}.msg{margin:1rem 0;padding:.75rem 1rem;border-radius:.5rem}\
+.user{background:#f6f8fa}.assistant{background:#eef6ee}.who{font-weight:600;margin-bottom:.25rem}\
+.text{white-space:pre-wrap;word-wrap:break-word}.meta{color:#6e7781;font-size:.85rem}";
+
+fn to_html(conversation: &Conversation, messages: &[ChatMessage]) -> String {
+    let title = escape_html(title(conversation));
+    let mut body = String::new();
+    for msg in messages.iter().filter(|x| !x.is_tool_step()) {
+        let (class, who) = match msg.role {
+            Some(Role::Assistant) => ("assistant", "Assistant"),
+            _ => ("user", "User"),
+        };
+        body.push_str(&format!(
+            "<div class=\"msg {class}\"><div class=\"who\">{who}</div><div class=\"text\">{}</div></div>\n",
+            escape_html(&msg.text)
+        ));
+    }
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+<style>{HTML_STYLE}</style>\n</head>\n<body>\n<h1>{title}</h1>\n<p class=\"meta\">{}</p>\n{body}</body>\n</html>\n",
+        format_datetime(conversation.created_at)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            super::escape_html("<a href=\"x\">&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+}
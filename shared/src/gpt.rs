@@ -1,25 +1,45 @@
-use crate::{get_env, get_env_or, network::*, resp_data, RespData, Result};
-use anyhow::{bail, Context};
-use async_openai::{
-    types::{
-        ChatCompletionRequestMessage, ChatCompletionRequestMessageArgs,
-        CreateChatCompletionRequest, Role,
+use crate::{
+    abort, budget, catalog, get_env, get_env_list, get_env_map, get_env_or,
+    network::*,
+    now_ms,
+    provider::{
+        chatgpt::{self, ChatGPT},
+        count_tokens, get_provider, get_provider_excluding, is_transient, keys, ChatRequest,
+        ChatStream, Provider, UpstreamError,
     },
-    Client, API_BASE,
+    resp_data,
+    sampling::{self, Sampling},
+    summary::{self, ContextStrategy},
+    tools::{self, Tool},
+    usage, RespData, Result, StructuredError,
+};
+use anyhow::{bail, Context};
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestMessageArgs, CreateChatCompletionRequest,
+    Role,
+};
+use futures::{
+    future::{AbortHandle, Abortable},
+    Future, StreamExt,
 };
-use futures::StreamExt;
 use serde_json::{json, Value};
-use tiktoken_rs::{async_openai::num_tokens_from_messages, model::get_context_size};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 pub const AUTH_SECRET_KEY: &str = "AUTH_SECRET_KEY";
 pub const TIMEOUT_ERROR: &str = "OpenAI timed out waiting for response";
+pub const CANCELLED: &str = "cancelled";
+pub const DISCONNECTED: &str = "disconnected";
+pub const CONTEXT_LENGTH_EXCEEDED: &str = "context_length_exceeded";
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
 pub struct RequestContext {
     #[serde(skip_serializing_if = "Option::is_none", rename = "conversationId")]
     pub conversation_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", rename = "parentMessageId")]
-    parent_message_id: Option<String>,
+    pub(crate) parent_message_id: Option<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
@@ -30,143 +50,883 @@ pub struct RequestOptions {
     pub last_context: RequestContext,
     #[serde(skip_serializing_if = "Option::is_none", rename = "systemMessage")]
     pub system_message: Option<String>,
+    /// Those not sent are the ones the conversation used last.
+    #[serde(flatten)]
+    pub sampling: Sampling,
+    /// Forget the sampling parameters the conversation used so far.
+    #[serde(default, rename = "resetSampling")]
+    pub reset_sampling: bool,
+    /// Must be one of `allowed_models()`, `OPENAI_API_MODEL` if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Names of `tools::enabled()` the model may call.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<String>,
+    /// Id of a past question `prompt` replaces, `lastContext` is taken from it and the
+    /// original branch is left as it is.
+    #[serde(skip_serializing_if = "Option::is_none", rename = "editMessageId")]
+    pub edit_message_id: Option<String>,
+    /// Tokens kept free for the answer, `max_response_tokens(model)` if not set.
+    #[serde(skip_serializing_if = "Option::is_none", rename = "maxResponseTokens")]
+    pub max_response_tokens: Option<usize>,
+    /// `CONTEXT_STRATEGY` if not set.
+    #[serde(skip_serializing_if = "Option::is_none", rename = "contextStrategy")]
+    pub context_strategy: Option<ContextStrategy>,
+    /// Set by the server from the `Authorization` header, for `TOKEN_BUDGET`.
+    #[serde(skip)]
+    pub access_token: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct FunctionCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: String,
+}
+
+/// How an answer was generated, for debugging and cost analysis.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f32>,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Counted with tiktoken because upstream reported no usage, e.g. when streaming.
+    pub estimated: bool,
+    /// From sending the request to the first token, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_token_ms: Option<u64>,
+    /// From sending the request to the end of the answer, in milliseconds.
+    pub latency_ms: u64,
+}
+
+impl Metadata {
+    /// Take upstream's `usage` over our estimate, summed over the requests of one answer.
+    pub(crate) fn add_usage(&mut self, usage: &Value) {
+        let (prompt, completion) = match (
+            usage["prompt_tokens"].as_u64(),
+            usage["completion_tokens"].as_u64(),
+        ) {
+            (Some(prompt), Some(completion)) => (prompt as usize, completion as usize),
+            _ => return,
+        };
+        if self.estimated {
+            self.estimated = false;
+            self.prompt_tokens = 0;
+            self.completion_tokens = 0;
+        }
+        self.prompt_tokens += prompt;
+        self.completion_tokens += completion;
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
 pub struct ChatMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
-    role: Option<Role>,
+    pub(crate) role: Option<Role>,
     #[serde(default, skip_serializing_if = "String::is_empty")]
-    id: String,
+    pub(crate) id: String,
     #[serde(default, skip_serializing_if = "String::is_empty")]
-    text: String,
+    pub(crate) text: String,
     #[serde(default, skip_serializing_if = "String::is_empty")]
-    delta: String,
+    pub(crate) delta: String,
+    /// Set on assistant messages asking to call a tool.
+    #[serde(skip_serializing_if = "Option::is_none", rename = "functionCall")]
+    pub(crate) function_call: Option<FunctionCall>,
+    /// Set on tool results, which carry the output in `text`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) name: Option<String>,
+    /// Upstream's `finish_reason`, or `cancelled` / `disconnected`.
+    #[serde(skip_serializing_if = "Option::is_none", rename = "finishReason")]
+    pub(crate) finish_reason: Option<String>,
+    /// The answer was cut short and `text` is only what was generated so far.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) incomplete: bool,
+    /// Milliseconds since the unix epoch, missing on messages from older versions.
+    #[serde(skip_serializing_if = "Option::is_none", rename = "createdAt")]
+    pub(crate) created_at: Option<u64>,
+    /// Model that wrote an answer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) model: Option<String>,
+    /// Set on answers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) metadata: Option<Metadata>,
+    /// Which of the `n` answers to one request this is, set when there are several.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) index: Option<u32>,
+    /// Budgets close to their limit, sent to the client before the answer.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) warnings: Vec<String>,
     #[serde(flatten)]
     pub last_context: RequestContext,
 }
 
-// https://github.com/transitive-bullshit/chatgpt-api#reverse-proxy
-// https://github.com/transitive-bullshit/chatgpt-api/blob/07dcc5df31476fb773a46d103136632e12762179/src/chatgpt-unofficial-proxy-api.ts#L174
-// https://github.com/Maxuss/chatgpt_rs/blob/cc2b9a56c937d1d5288d7d2507c02d40c83cadbc/src/client.rs#L67
-fn get_client(url: &str, key: &str) -> Client {
-    let mut client = Client::new().with_api_key(key);
-    if let Some(c) = build_proxy_client() {
-        client = client.with_http_client(c);
+impl ChatMessage {
+    /// Tool calls and results are kept in the store but not replayed as context.
+    pub(crate) fn is_tool_step(&self) -> bool {
+        self.function_call.is_some() || self.name.is_some()
     }
-    if !url.is_empty() {
-        client = client.with_api_base(url);
+
+    pub fn finish_reason(&self) -> Option<&str> {
+        self.finish_reason.as_deref()
+    }
+
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
     }
-    client
-}
 
-fn get_default_client() -> Client {
-    get_client(&get_url(), &get_key())
+    /// Mark as stopped by the user and tell the client.
+    async fn set_cancelled<F, Fut>(&mut self, on_progress: Option<&F>)
+    where
+        F: Fn(ChatMessage) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        self.finish_reason = Some(CANCELLED.to_owned());
+        self.incomplete = true;
+        if let Some(on_progress) = on_progress {
+            on_progress(ChatMessage {
+                text: "".to_owned(),
+                delta: "".to_owned(),
+                ..self.clone()
+            })
+            .await
+            .ok();
+        }
+    }
+
+    /// Mark as stopped because nobody is listening any more.
+    fn set_disconnected(&mut self) {
+        self.finish_reason = Some(DISCONNECTED.to_owned());
+        self.incomplete = true;
+    }
 }
 
-fn get_request(opt: RequestOptions, stream: Option<bool>) -> Result<CreateChatCompletionRequest> {
+async fn get_request(
+    provider: &dyn Provider,
+    opt: RequestOptions,
+    stream: Option<bool>,
+) -> Result<(ChatRequest, usize)> {
     log::debug!("Request options: {:?}", opt);
-    let model = get_model();
-    let temperature = opt.temperature;
-    let top_p = opt.temperature;
-    let (messages, max_tokens, _) = build_messages(&model, opt)?;
+    let model = resolve_model(opt.model.as_deref())?;
+    let sampling = opt.sampling.clone();
+    let strategy = opt
+        .context_strategy
+        .unwrap_or_else(ContextStrategy::from_env);
+    let conversation_id = opt.last_context.conversation_id.clone();
+    let system_message_offset = opt.system_message.is_some() as usize;
+    let user = usage::user_id(&opt.access_token);
+    let reserved = match strategy {
+        ContextStrategy::Truncate => 0,
+        ContextStrategy::Summarize => summary::max_tokens(),
+    };
+    let (mut messages, mut max_tokens, mut num_tokens, dropped_from) =
+        build_messages(provider, &model, opt, reserved)?;
+    if let (ContextStrategy::Summarize, Some(id), Some(conversation_id)) =
+        (strategy, dropped_from, conversation_id)
+    {
+        let dropped: Vec<ChatMessage> = crate::history::path(&id)?
+            .into_iter()
+            .filter(|x| !x.is_tool_step())
+            .collect();
+        let context_size = catalog::context_window(provider, &model);
+        // better a truncated history than no answer
+        match summary::summarize(&conversation_id, &dropped, &model, context_size, &user).await {
+            Ok(text) if !text.is_empty() => {
+                messages.insert(
+                    system_message_offset,
+                    ChatCompletionRequestMessageArgs::default()
+                        .content(summary::as_system_message(&text))
+                        .role(Role::System)
+                        .build()?,
+                );
+                num_tokens = catalog::num_tokens(provider, &model, &messages)?;
+                max_tokens = max_tokens
+                    .min(context_size.saturating_sub(num_tokens))
+                    .max(1);
+            }
+            Ok(_) => {}
+            Err(err) => log::warn!("Summarize conversation {conversation_id}: {err}"),
+        }
+    }
     log::debug!("Send messages to OpenAI: {:?}", messages);
-    let req = CreateChatCompletionRequest {
+    let inner = CreateChatCompletionRequest {
         model,
         max_tokens: Some(max_tokens as _),
         messages,
-        temperature,
-        top_p,
+        temperature: sampling.temperature,
+        top_p: sampling.top_p,
+        presence_penalty: sampling.presence_penalty,
+        frequency_penalty: sampling.frequency_penalty,
+        stop: sampling.stop(),
+        logit_bias: sampling.logit_bias(),
+        user: sampling.user.clone(),
+        n: sampling.n,
         stream,
-        ..Default::default()
     };
-    Ok(req)
+    let req = ChatRequest {
+        inner,
+        seed: sampling.seed,
+    };
+    Ok((req, num_tokens))
 }
 
 // https://github.com/64bit/async-openai/blob/main/examples/chat-stream/src/main.rs
-pub async fn chat_process<F>(
+/// `on_progress` fails once the client is gone, the answer then stops and is stored as
+/// incomplete.
+pub async fn chat_process<F, Fut>(
     opt: RequestOptions,
     on_progress: Option<F>,
 ) -> Result<RespData<ChatMessage>>
 where
-    F: Fn(ChatMessage),
+    F: Fn(ChatMessage) -> Fut,
+    Fut: Future<Output = Result<()>>,
 {
-    let stream = if on_progress.is_none() {
-        None
-    } else {
-        Some(true)
-    };
+    let mut opt = opt;
+    if let Some(id) = opt.edit_message_id.take() {
+        let msg = get_message(&id).with_context(|| format!("Message {id} not found"))?;
+        if matches!(msg.role, Some(Role::Assistant)) || msg.is_tool_step() {
+            bail!("Message {id} is not a question");
+        }
+        // the new question becomes a sibling of the old one
+        opt.last_context = msg.last_context;
+    }
+    // both end up in store keys
+    let context = &opt.last_context;
+    for id in [&context.conversation_id, &context.parent_message_id]
+        .into_iter()
+        .flatten()
+    {
+        crate::history::check_id(id)?;
+    }
+    check_budget(&opt.access_token, on_progress.as_ref()).await?;
+    if chatgpt::is_enabled() {
+        if opt.sampling.n.unwrap_or(1) > 1 {
+            bail!("API_REVERSE_PROXY gives a single answer, n must be 1");
+        }
+        return chat_process_unofficial(opt, on_progress).await;
+    }
+    // a new conversation, the ChatGPT backend would assign the id otherwise
+    let conversation_id = opt
+        .last_context
+        .conversation_id
+        .get_or_insert_with(|| uuid::Uuid::new_v4().to_string());
+    opt.sampling = sampling::remember(conversation_id, opt.sampling, opt.reset_sampling)?;
     let last_msg = ChatMessage {
         id: uuid::Uuid::new_v4().to_string(),
+        created_at: Some(now_ms()),
         last_context: opt.last_context.clone(),
         text: opt.prompt.clone(),
         ..Default::default()
     };
+    let (mut answers, steps) = reply(&last_msg, opt, on_progress).await?;
+    if put_message(&last_msg).is_ok() {
+        steps.iter().for_each(|x| {
+            put_message(x).ok();
+        });
+        put_answers(&answers);
+    }
+    Ok(resp_data(answers.swap_remove(0)))
+}
 
-    let mut result = ChatMessage {
-        role: Some(Role::Assistant),
-        id: uuid::Uuid::new_v4().to_string(),
-        last_context: RequestContext {
-            conversation_id: opt.last_context.conversation_id.clone(),
-            parent_message_id: Some(last_msg.id.clone()),
-        },
-        ..Default::default()
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct RegenerateOptions {
+    /// The assistant message to answer again.
+    pub id: String,
+    /// `prompt` and `lastContext` are ignored, they come from the original question.
+    #[serde(flatten)]
+    pub options: RequestOptions,
+}
+
+/// Answer the question of assistant message `id` again, the new answer is stored as a
+/// sibling of the old one so both stay around.
+pub async fn chat_regenerate<F, Fut>(
+    opt: RegenerateOptions,
+    on_progress: Option<F>,
+) -> Result<RespData<ChatMessage>>
+where
+    F: Fn(ChatMessage) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    if chatgpt::is_enabled() {
+        bail!("Regenerate is not supported with API_REVERSE_PROXY");
+    }
+    check_budget(&opt.options.access_token, on_progress.as_ref()).await?;
+    let msg = get_message(&opt.id).with_context(|| format!("Message {} not found", opt.id))?;
+    if !matches!(msg.role, Some(Role::Assistant)) || msg.is_tool_step() {
+        bail!("Message {} is not an answer", opt.id);
+    }
+    // skip the tool calls between the answer and its question
+    let mut parent_message_id = msg.last_context.parent_message_id;
+    let question = loop {
+        let id = parent_message_id.context("The answer has no question")?;
+        let parent = get_message(&id).with_context(|| format!("Message {id} not found"))?;
+        if !parent.is_tool_step() {
+            break parent;
+        }
+        parent_message_id = parent.last_context.parent_message_id;
+    };
+    let mut opt = RequestOptions {
+        prompt: question.text.clone(),
+        last_context: question.last_context.clone(),
+        ..opt.options
     };
+    if let Some(conversation_id) = &question.last_context.conversation_id {
+        opt.sampling = sampling::remember(conversation_id, opt.sampling, opt.reset_sampling)?;
+    } else {
+        opt.sampling.validate()?;
+    }
+    let (mut answers, steps) = reply(&question, opt, on_progress).await?;
+    steps.iter().for_each(|x| {
+        put_message(x).ok();
+    });
+    put_answers(&answers);
+    Ok(resp_data(answers.swap_remove(0)))
+}
+
+/// Fail when a budget is used up, tell the client about those running low.
+async fn check_budget<F, Fut>(access_token: &str, on_progress: Option<&F>) -> Result<()>
+where
+    F: Fn(ChatMessage) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let warnings = budget::check(access_token)?;
+    if let Some(on_progress) = on_progress {
+        if !warnings.is_empty() {
+            on_progress(ChatMessage {
+                warnings,
+                ..Default::default()
+            })
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Answer `last_msg`, whose text and context are in `opt`. Nothing is stored here, the
+/// answers (one unless `n` asks for more) come back with the tool calls that led to them.
+async fn reply<F, Fut>(
+    last_msg: &ChatMessage,
+    opt: RequestOptions,
+    on_progress: Option<F>,
+) -> Result<(Vec<ChatMessage>, Vec<ChatMessage>)>
+where
+    F: Fn(ChatMessage) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let model = resolve_model(opt.model.as_deref())?;
+    let info = catalog::get(&model);
+    // models which can't stream answer in one piece, reported when done
+    let stream = if on_progress.is_none() || !info.supports_streaming() {
+        None
+    } else {
+        Some(true)
+    };
+    let n = opt.sampling.n.unwrap_or(1) as usize;
     let timeout = get_timeout_ms();
-    let request = get_request(opt, stream)?;
-    match on_progress {
-        Some(on_progress) => {
-            log::debug!("Start chat stream");
-            let mut stream =
-                crate::timeout(timeout, get_default_client().chat().create_stream(request))
-                    .await
-                    .context(TIMEOUT_ERROR)??;
-            log::debug!("Start chat stream loop");
-            // https://github.com/64bit/async-openai/blob/f6b04b54d5627a18a1f3c376f878290b92ef571a/examples/chat-stream/src/main.rs#L38
-            loop {
-                let _res = crate::timeout(timeout, stream.next()).await?;
-                match _res {
-                    Some(Ok(resp)) => {
-                        let mut resp = resp;
-                        if !resp.choices.is_empty() {
-                            let delta = resp.choices.drain(..).nth(0).unwrap().delta;
-                            result.delta = delta.content.unwrap_or_default();
-                            let text = result.text;
-                            result.text = "".to_owned(); // saving bandwidth
-                            if delta.role.is_some() {
-                                result.role = delta.role;
+    let provider = get_provider()?;
+    let tools = tools::resolve(&opt.tools)?;
+    if n > 1 && !tools.is_empty() {
+        bail!("Tools can only be used with a single answer");
+    }
+    if n > 1 && !provider.supports_n() {
+        bail!("{} gives a single answer, n must be 1", provider.name());
+    }
+    if !tools.is_empty() && !info.supports_functions() {
+        bail!("Model {model} does not support tools");
+    }
+    let user = usage::user_id(&opt.access_token);
+    let started = Instant::now();
+    let (request, prompt_tokens) = get_request(provider.as_ref(), opt, stream).await?;
+    let mut answers: Vec<ChatMessage> = (0..n)
+        .map(|i| ChatMessage {
+            role: Some(Role::Assistant),
+            id: uuid::Uuid::new_v4().to_string(),
+            created_at: Some(now_ms()),
+            model: Some(request.model.clone()),
+            index: (n > 1).then_some(i as u32),
+            last_context: RequestContext {
+                conversation_id: last_msg.last_context.conversation_id.clone(),
+                parent_message_id: Some(last_msg.id.clone()),
+            },
+            ..Default::default()
+        })
+        .collect();
+    let mut metadata = Metadata {
+        temperature: request.temperature,
+        top_p: request.top_p,
+        prompt_tokens,
+        estimated: true,
+        ..Default::default()
+    };
+    let mut used_key = String::new();
+    // kept out of the abortable part, the answer already hangs below the last step when
+    // the request is cancelled during a tool call
+    let mut steps = vec![];
+    let (handle, registration) = AbortHandle::new_pair();
+    // aborting any of the answers stops them all, they come from one request
+    let _running: Vec<_> = answers
+        .iter()
+        .map(|x| abort::register(&x.id, handle.clone()))
+        .collect();
+    let process = async {
+        match on_progress.as_ref() {
+            on_progress if !tools.is_empty() => {
+                call_functions(
+                    request,
+                    &tools,
+                    &mut answers[0],
+                    &mut steps,
+                    &mut metadata,
+                    &mut used_key,
+                    on_progress,
+                )
+                .await?;
+            }
+            Some(on_progress) if stream.is_some() => {
+                log::debug!("Start {} chat stream", provider.name());
+                let mut stream = upstream(&mut used_key, |provider| {
+                    open_stream(provider, request.clone())
+                })
+                .await?;
+                log::debug!("Start chat stream loop");
+                // https://github.com/64bit/async-openai/blob/f6b04b54d5627a18a1f3c376f878290b92ef571a/examples/chat-stream/src/main.rs#L38
+                loop {
+                    let _res = crate::timeout(timeout, stream.next()).await?;
+                    match _res {
+                        Some(Ok(resp)) => {
+                            set_model(&mut answers, &resp.model);
+                            let mut disconnected = false;
+                            for choice in resp.choices {
+                                let result = match answers.get_mut(choice.index as usize) {
+                                    Some(x) => x,
+                                    None => continue,
+                                };
+                                let delta = choice.delta;
+                                result.delta = delta.content.unwrap_or_default();
+                                // complete before sending, an abort may drop the send
+                                result.text.push_str(&result.delta);
+                                if delta.role.is_some() {
+                                    result.role = delta.role;
+                                }
+                                if choice.finish_reason.is_some() {
+                                    result.finish_reason = choice.finish_reason;
+                                }
+                                if !result.delta.is_empty() && metadata.first_token_ms.is_none() {
+                                    metadata.first_token_ms =
+                                        Some(started.elapsed().as_millis() as u64);
+                                }
+                                let sent = on_progress(ChatMessage {
+                                    text: String::new(), // saving bandwidth
+                                    ..result.clone()
+                                })
+                                .await;
+                                if sent.is_err() {
+                                    log::debug!("Chat {} lost its client", result.id);
+                                    disconnected = true;
+                                    break;
+                                }
+                            }
+                            if disconnected {
+                                answers.iter_mut().for_each(|x| x.set_disconnected());
+                                break;
                             }
-                            on_progress(result.clone());
-                            result.text = format!("{}{}", text, result.delta);
                         }
+                        Some(Err(err)) => bail!(err),
+                        None => break,
                     }
-                    Some(Err(err)) => bail!(err),
-                    None => break,
                 }
             }
+            _ => {
+                let resp = upstream(&mut used_key, |provider| {
+                    let request = request.clone();
+                    async move { provider.chat(request).await }
+                })
+                .await?;
+                metadata.add_usage(&serde_json::to_value(&resp.usage)?);
+                set_model(&mut answers, &resp.model);
+                for msg in resp.choices {
+                    if let Some(result) = answers.get_mut(msg.index as usize) {
+                        result.role = Some(msg.message.role);
+                        result.text = msg.message.content;
+                        result.finish_reason = msg.finish_reason;
+                    }
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    let cancelled = match Abortable::new(process, registration).await {
+        Ok(res) => {
+            res?;
+            false
+        }
+        Err(_) => true,
+    };
+    // OpenAI compatible servers may ignore `n`, answers they never filled are dropped
+    if n > 1 {
+        answers.retain(|x| x.index == Some(0) || !x.text.is_empty());
+    }
+    if cancelled {
+        log::debug!("Chat {} cancelled", answers[0].id);
+        for result in answers.iter_mut() {
+            result.set_cancelled(on_progress.as_ref()).await;
         }
-        None => {
-            // always use the first one: https://github.com/transitive-bullshit/chatgpt-api/blob/bf66500730d0ab4c2388250f3ddac17bf5408df5/src/chatgpt-api.ts#L280
-            let mut resp = crate::timeout(timeout, get_default_client().chat().create(request))
-                .await
-                .context(TIMEOUT_ERROR)??;
-            if !resp.choices.is_empty() {
-                let msg = resp.choices.drain(..).nth(0).unwrap();
-                result.role = Some(msg.message.role);
-                result.text = msg.message.content;
+    }
+    metadata.latency_ms = started.elapsed().as_millis() as u64;
+    let mut total = metadata.clone();
+    if metadata.estimated {
+        total.completion_tokens = answers.iter().map(|x| count_tokens(&x.text)).sum();
+    }
+    for result in answers.iter_mut() {
+        let mut metadata = metadata.clone();
+        // upstream only reports the tokens of all answers together
+        if metadata.estimated || n > 1 {
+            metadata.completion_tokens = count_tokens(&result.text);
+            metadata.estimated = true;
+        }
+        result.metadata = Some(metadata);
+        result.delta.clear();
+    }
+    if let Some(on_progress) = on_progress.as_ref() {
+        // the first answer is reported by the caller, the others are done now
+        for result in answers.iter().skip(1) {
+            on_progress(ChatMessage {
+                text: "".to_owned(),
+                ..result.clone()
+            })
+            .await
+            .ok();
+        }
+    }
+    let account = usage_account(provider.name(), &used_key);
+    let request_usage = ChatMessage {
+        metadata: Some(total),
+        ..answers[0].clone()
+    };
+    usage::record(&request_usage, &account, &user).ok();
+    Ok((answers, steps))
+}
+
+/// Call upstream with a fresh provider, moving on to another pooled key while keys are
+/// rate limited and retrying transient failures with exponential backoff.
+///
+/// `used_key` is set to the key which finally answered.
+pub(crate) async fn upstream<T, F, Fut>(used_key: &mut String, call: F) -> Result<T>
+where
+    F: Fn(Arc<dyn Provider>) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let timeout = get_timeout_ms();
+    let max_retries: u32 = get_env("RETRY_MAX").parse().unwrap_or(2);
+    let mut retries = 0;
+    // keys over their budget are never tried
+    let exhausted = budget::exhausted_keys()?;
+    let mut tried = exhausted.clone();
+    loop {
+        let provider: Arc<dyn Provider> = get_provider_excluding(&tried)?.into();
+        let err = match crate::timeout(timeout, call(provider.clone()))
+            .await
+            .context(TIMEOUT_ERROR)?
+        {
+            Ok(x) => {
+                *used_key = provider.api_key().to_owned();
+                return Ok(x);
             }
+            Err(err) => err,
+        };
+        let key = provider.api_key();
+        if !key.is_empty() && keys::is_rate_limit(&err) {
+            keys::mark_limited(key, &err);
+            tried.push(key.to_owned());
+            if keys::get_keys().iter().any(|x| !tried.contains(x)) {
+                log::info!("Retry with another key");
+                continue;
+            }
+        }
+        if retries >= max_retries || !is_transient(&err) {
+            return Err(err);
         }
+        let delay = match retry_delay(retries, &err) {
+            Some(delay) => delay,
+            None => return Err(err),
+        };
+        retries += 1;
+        log::info!("Retry in {delay:?} after: {err}");
+        tokio::time::sleep(delay).await;
+        // every key may be cooling down by now, let the pool pick the first to recover
+        tried = exhausted.clone();
+    }
+}
+
+/// Exponential backoff with jitter, or upstream's `Retry-After`. `None` if that is longer
+/// than we are willing to wait.
+fn retry_delay(retries: u32, err: &anyhow::Error) -> Option<Duration> {
+    use std::hash::{BuildHasher, Hasher};
+    let base: u64 = get_env("RETRY_BASE_MS").parse().unwrap_or(500);
+    let max: u64 = get_env("RETRY_MAX_DELAY_MS").parse().unwrap_or(20 * 1000);
+    if let Some(UpstreamError {
+        retry_after: Some(x),
+        ..
+    }) = err.downcast_ref::<UpstreamError>()
+    {
+        return (x.as_millis() as u64 <= max).then_some(*x);
     }
+    let delay = base.saturating_mul(1 << retries.min(16)).min(max);
+    // RandomState is seeded randomly, good enough for jitter
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    Some(Duration::from_millis(delay / 2 + random % (delay / 2 + 1)))
+}
+
+/// Open the stream and wait for its first chunk, so that failures before anything reached
+/// `on_progress` can still be retried.
+async fn open_stream(provider: Arc<dyn Provider>, request: ChatRequest) -> Result<ChatStream> {
+    let mut stream = provider.chat_stream(request).await?;
+    let first = match stream.next().await {
+        Some(Err(err)) => return Err(err),
+        first => first,
+    };
+    Ok(futures::stream::iter(first).chain(stream).boxed())
+}
+
+// https://github.com/transitive-bullshit/chatgpt-api/blob/07dcc5df31476fb773a46d103136632e12762179/src/chatgpt-unofficial-proxy-api.ts#L99
+async fn chat_process_unofficial<F, Fut>(
+    opt: RequestOptions,
+    on_progress: Option<F>,
+) -> Result<RespData<ChatMessage>>
+where
+    F: Fn(ChatMessage) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut last_msg = ChatMessage {
+        role: Some(Role::User),
+        id: uuid::Uuid::new_v4().to_string(),
+        created_at: Some(now_ms()),
+        text: opt.prompt.clone(),
+        last_context: opt.last_context.clone(),
+        ..Default::default()
+    };
+    let parent_message_id = opt
+        .last_context
+        .parent_message_id
+        .clone()
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let timeout = get_timeout_ms();
+    let chatgpt = ChatGPT::from_env();
+    let started = Instant::now();
+    let mut first_token_ms = None;
+    let mut stream = crate::timeout(
+        timeout,
+        chatgpt.conversation(
+            &opt.prompt,
+            &last_msg.id,
+            &parent_message_id,
+            opt.last_context.conversation_id.as_deref(),
+        ),
+    )
+    .await
+    .context(TIMEOUT_ERROR)??;
+    let mut result = ChatMessage {
+        role: Some(Role::Assistant),
+        created_at: Some(now_ms()),
+        model: Some(chatgpt.model().to_owned()),
+        ..Default::default()
+    };
+    let (handle, registration) = AbortHandle::new_pair();
+    let mut running = None;
+    let process = async {
+        while let Some(event) = crate::timeout(timeout, stream.next())
+            .await
+            .context(TIMEOUT_ERROR)?
+        {
+            let event = event?;
+            // the id is only known once upstream answers
+            if running.is_none() {
+                running = Some(abort::register(&event.message_id, handle.clone()));
+            }
+            // the backend sends the whole answer every time
+            let delta = event
+                .text
+                .strip_prefix(result.text.as_str())
+                .unwrap_or_default()
+                .to_owned();
+            result.id = event.message_id;
+            result.last_context = RequestContext {
+                conversation_id: Some(event.conversation_id),
+                parent_message_id: Some(last_msg.id.clone()),
+            };
+            result.text = event.text;
+            if !delta.is_empty() && first_token_ms.is_none() {
+                first_token_ms = Some(started.elapsed().as_millis() as u64);
+            }
+            if let Some(on_progress) = &on_progress {
+                if !delta.is_empty() {
+                    let sent = on_progress(ChatMessage {
+                        text: "".to_owned(),
+                        delta,
+                        ..result.clone()
+                    })
+                    .await;
+                    if sent.is_err() {
+                        result.set_disconnected();
+                        break;
+                    }
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    match Abortable::new(process, registration).await {
+        Ok(res) => res?,
+        Err(_) => result.set_cancelled(on_progress.as_ref()).await,
+    }
+    if result.id.is_empty() {
+        bail!("No answer from API_REVERSE_PROXY");
+    }
+    // the backend reports no usage and only knows the history by id
+    result.metadata = Some(Metadata {
+        prompt_tokens: count_tokens(&last_msg.text),
+        completion_tokens: count_tokens(&result.text),
+        estimated: true,
+        first_token_ms,
+        latency_ms: started.elapsed().as_millis() as u64,
+        ..Default::default()
+    });
+    usage::record(&result, "chatgpt", &usage::user_id(&opt.access_token)).ok();
+    last_msg.last_context.conversation_id = result.last_context.conversation_id.clone();
     if put_message(&last_msg).is_ok() {
         put_message(&result).ok();
     }
     Ok(resp_data(result))
 }
 
+/// Most function calls one request may chain before giving up.
+const MAX_FUNCTION_CALLS: usize = 8;
+
+/// Let the model call `tools` until it answers, the calls and their outputs are pushed to
+/// `steps` as they happen and `result` moved below the last of them.
+// https://platform.openai.com/docs/guides/gpt/function-calling
+async fn call_functions<F, Fut>(
+    request: ChatRequest,
+    tools: &[Tool],
+    result: &mut ChatMessage,
+    steps: &mut Vec<ChatMessage>,
+    metadata: &mut Metadata,
+    used_key: &mut String,
+    on_progress: Option<&F>,
+) -> Result<()>
+where
+    F: Fn(ChatMessage) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut body = serde_json::to_value(&request)?;
+    if let Some(body) = body.as_object_mut() {
+        body.remove("stream");
+        // tool output is not part of the budget computed by build_messages
+        body.remove("max_tokens");
+    }
+    body["functions"] = json!(tools.iter().map(|x| x.schema()).collect::<Vec<_>>());
+    for _ in 0..MAX_FUNCTION_CALLS {
+        let resp = upstream(&mut *used_key, |provider| {
+            let body = body.clone();
+            async move { provider.chat_value(body).await }
+        })
+        .await?;
+        metadata.add_usage(&resp["usage"]);
+        let model = resp["model"].as_str().unwrap_or_default();
+        set_model(std::slice::from_mut(result), model);
+        let message = &resp["choices"][0]["message"];
+        let call = match serde_json::from_value::<FunctionCall>(message["function_call"].clone()) {
+            Ok(call) => call,
+            Err(_) => {
+                result.text = message["content"].as_str().unwrap_or_default().to_owned();
+                if let Some(on_progress) = on_progress {
+                    result.delta = result.text.clone();
+                    let sent = on_progress(ChatMessage {
+                        text: "".to_owned(),
+                        ..result.clone()
+                    })
+                    .await;
+                    if sent.is_err() {
+                        result.set_disconnected();
+                    }
+                }
+                return Ok(());
+            }
+        };
+        log::debug!("Call function {} with {}", call.name, call.arguments);
+        let call_msg = ChatMessage {
+            role: Some(Role::Assistant),
+            id: uuid::Uuid::new_v4().to_string(),
+            created_at: Some(now_ms()),
+            function_call: Some(call.clone()),
+            last_context: result.last_context.clone(),
+            ..Default::default()
+        };
+        if let Some(on_progress) = on_progress {
+            if on_progress(call_msg.clone()).await.is_err() {
+                result.set_disconnected();
+                return Ok(());
+            }
+        }
+        let output = match tools.iter().find(|x| x.name == call.name) {
+            Some(tool) => tool.call(&call.arguments).await,
+            None => format!("Error: unknown function {}", call.name),
+        };
+        let output_msg = ChatMessage {
+            id: uuid::Uuid::new_v4().to_string(),
+            created_at: Some(now_ms()),
+            text: output.clone(),
+            name: Some(call.name.clone()),
+            last_context: RequestContext {
+                conversation_id: result.last_context.conversation_id.clone(),
+                parent_message_id: Some(call_msg.id.clone()),
+            },
+            ..Default::default()
+        };
+        if let Some(messages) = body["messages"].as_array_mut() {
+            messages.push(json!({"role": "assistant", "content": null, "function_call": call}));
+            messages.push(json!({"role": "function", "name": call.name, "content": output}));
+        }
+        result.last_context.parent_message_id = Some(output_msg.id.clone());
+        steps.push(call_msg);
+        steps.push(output_msg.clone());
+        if let Some(on_progress) = on_progress {
+            if on_progress(output_msg).await.is_err() {
+                result.set_disconnected();
+                return Ok(());
+            }
+        }
+    }
+    bail!("Too many function calls")
+}
+
+/// The model upstream says answered, e.g. `gpt-4-0613` for `gpt-4`, the requested one is
+/// kept when it says nothing.
+fn set_model(answers: &mut [ChatMessage], model: &str) {
+    if model.is_empty() {
+        return;
+    }
+    for result in answers.iter_mut() {
+        if result.model.as_deref() != Some(model) {
+            result.model = Some(model.to_owned());
+        }
+    }
+}
+
+/// Usage is accounted per `keys::account_id`, or per provider for keyless ones.
+pub(crate) fn usage_account(provider: &str, used_key: &str) -> String {
+    if used_key.is_empty() {
+        provider.to_owned()
+    } else {
+        keys::account_id(used_key)
+    }
+}
+
 #[inline]
 fn get_timeout_ms() -> u64 {
     let i: i32 = get_env("TIMEOUT_MS").parse().unwrap_or(0);
@@ -177,85 +937,114 @@ fn get_timeout_ms() -> u64 {
     }
 }
 
-#[inline]
-fn get_key() -> String {
-    get_env("OPENAI_API_KEY")
-}
-
 #[inline]
 fn get_model() -> String {
     get_env_or("OPENAI_API_MODEL", "gpt-3.5-turbo")
 }
 
-fn get_url() -> String {
-    get_env_or("API_REVERSE_PROXY", get_env("OPENAI_API_BASE_URL"))
+/// Models clients may pick per request, `ALLOWED_MODELS` or just the default model.
+pub fn allowed_models() -> Vec<String> {
+    let mut models = get_env_list("ALLOWED_MODELS");
+    let model = get_model();
+    if !models.contains(&model) {
+        models.insert(0, model);
+    }
+    models
+}
+
+fn resolve_model(model: Option<&str>) -> Result<String> {
+    match model {
+        None | Some("") => Ok(get_model()),
+        Some(model) => {
+            if !allowed_models().iter().any(|x| x == model) {
+                bail!("Model {model} is not allowed");
+            }
+            Ok(model.to_owned())
+        }
+    }
 }
 
+/// Inclusive `YYYY-MM-DD` dates, open ended when empty.
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
 pub struct DateRange {
-    start_date: String,
-    end_date: String,
-}
-
-pub async fn fetch_usage(rng: DateRange) -> Result<String> {
-    let mut url = get_url();
-    if url.is_empty() {
-        url = API_BASE.to_owned();
-    }
-    let key = get_key();
-
-    let url_usage = format!(
-        "${url}/dashboard/billing/usage?start_date=${}&end_date=${}",
-        rng.start_date, rng.end_date
-    );
-    let client = build_proxy_client().unwrap_or(reqwest::Client::new());
-    let res = client
-        .get(url_usage)
-        .header("Authorization", format!("Bearer ${key}"))
-        .header("Content-Type", "application/json")
-        .send()
-        .await?;
-    Ok(res.text().await?)
+    #[serde(default)]
+    pub start_date: String,
+    #[serde(default)]
+    pub end_date: String,
 }
 
 pub async fn chat_config(rng: DateRange, for_web: bool) -> Result<RespData<Value>> {
-    let usage = fetch_usage(rng).await.unwrap_or_default();
+    let provider = get_provider()?;
+    // counted locally, the billing dashboard no longer accepts API keys
+    let mut usage = usage::summary(&rng)?;
+    if for_web {
+        usage.by_user.clear();
+    }
     let reverse = get_env("API_REVERSE_PROXY");
     let mut proxy = get_env("PROXY");
     if for_web {
         proxy = remove_auth(proxy);
     }
     let mut data = json!({
+        "provider": provider.name(),
         "reverseProxy": reverse,
         "proxy": proxy,
-        "usage": usage,
+        "usage": format!("${:.4}", usage.total.cost),
+        "usageDetails": usage,
         "timeoutMs": get_timeout_ms(),
     });
+    let provider_config = provider.config();
+    if !provider_config.is_null() {
+        data[provider.name()] = provider_config;
+    }
     if !for_web {
-        data["apiKey"] = json!(get_key());
+        // the whole pool, saving the setting writes it back as is
+        data["apiKey"] = json!(keys::get_keys().join(","));
     }
     Ok(resp_data(data))
 }
 
+/// Tokens kept free for the answer, the longest matching prefix of `MAX_RESPONSE_TOKENS`
+/// (`model=tokens`) or the model catalog, 1000 if unknown.
+// https://github.com/transitive-bullshit/chatgpt-api/blob/bf66500730d0ab4c2388250f3ddac17bf5408df5/src/chatgpt-api.ts#L44
+pub fn max_response_tokens(model: &str) -> usize {
+    let custom: Vec<(String, usize)> = get_env_map("MAX_RESPONSE_TOKENS")
+        .into_iter()
+        .filter_map(|(k, v)| Some((k, v.parse().ok()?)))
+        .collect();
+    custom
+        .iter()
+        .filter(|(k, _)| model.starts_with(k.as_str()))
+        .max_by_key(|(k, _)| k.len())
+        .map(|(_, v)| *v)
+        .or_else(|| catalog::get(model).max_output)
+        .unwrap_or(1000)
+}
+
 // https://github.com/transitive-bullshit/chatgpt-api/blob/bf66500730d0ab4c2388250f3ddac17bf5408df5/src/chatgpt-api.ts#L361
+/// Messages of the request, its `max_tokens` and prompt tokens, and the newest message of
+/// the history left out for lack of room. `reserved` tokens are kept free for a summary.
+/// Fewest tokens an answer is given before the prompt counts as too long.
+const MIN_RESPONSE_TOKENS: usize = 64;
+
 fn build_messages(
+    provider: &dyn Provider,
     model: &str,
     opt: RequestOptions,
-) -> Result<(Vec<ChatCompletionRequestMessage>, usize, usize)> {
-    // https://github.com/transitive-bullshit/chatgpt-api/blob/bf66500730d0ab4c2388250f3ddac17bf5408df5/src/chatgpt-api.ts#L44
-    let max_model_tokens = get_context_size(model);
-    let max_response_tokens = if model.contains("gpt-4") {
-        // if use 32k model
-        if model.contains("32k") {
-            8192
-        } else {
-            2048
-        }
-    } else {
-        // https://github.com/transitive-bullshit/chatgpt-api/blob/bf66500730d0ab4c2388250f3ddac17bf5408df5/src/chatgpt-api.ts#L44
-        1000
+    reserved: usize,
+) -> Result<(
+    Vec<ChatCompletionRequestMessage>,
+    usize,
+    usize,
+    Option<String>,
+)> {
+    let max_model_tokens = catalog::context_window(provider, model);
+    let max_response_tokens = match opt.max_response_tokens {
+        Some(0) => bail!("maxResponseTokens must be positive"),
+        Some(x) => x,
+        None => max_response_tokens(model),
     };
-    let max_num_tokens = max_model_tokens - max_response_tokens;
+    let max_num_tokens = max_model_tokens.saturating_sub(max_response_tokens + reserved);
     let mut messages = vec![];
     if let Some(msg) = opt.system_message {
         messages.push(
@@ -277,13 +1066,35 @@ fn build_messages(
     } else {
         messages.clone()
     };
-    let mut num_tokens = 0;
+    let prompt_tokens = catalog::num_tokens(provider, model, &messages)?;
+    // a long prompt gets a shorter answer, but not one cut off right away
+    if prompt_tokens + max_response_tokens.min(MIN_RESPONSE_TOKENS) > max_model_tokens {
+        return Err(StructuredError {
+            code: CONTEXT_LENGTH_EXCEEDED,
+            message: format!(
+                "The prompt takes {prompt_tokens} of the {max_model_tokens} tokens of {model}, \
+                 too many to leave room for an answer"
+            ),
+            details: json!({
+                "model": model,
+                "promptTokens": prompt_tokens,
+                "maxResponseTokens": max_response_tokens,
+                "contextSize": max_model_tokens,
+            }),
+        }
+        .into());
+    }
+    let mut num_tokens = prompt_tokens;
     let mut parent_message_id = opt.last_context.parent_message_id;
+    // the message added last to next_messages
+    let mut added = None;
+    let mut dropped_from = None;
     loop {
-        let next_num_tokens_estimate = num_tokens_from_messages(model, &next_messages)?;
+        let next_num_tokens_estimate = catalog::num_tokens(provider, model, &next_messages)?;
         let is_valid_prompt = next_num_tokens_estimate <= max_num_tokens;
 
         if !next_messages.is_empty() && !is_valid_prompt {
+            dropped_from = added;
             break;
         }
         messages = next_messages.clone();
@@ -299,23 +1110,30 @@ fn build_messages(
         match get_message(&parent_message_id.unwrap()) {
             None => break,
             Some(msg) => {
-                let role = msg.role.unwrap_or(Role::User);
-                next_messages.insert(
-                    system_message_offset,
-                    ChatCompletionRequestMessageArgs::default()
-                        .content(msg.text)
-                        .role(role)
-                        .build()?,
-                );
+                added = Some(msg.id.clone());
+                if !msg.is_tool_step() {
+                    let role = msg.role.unwrap_or(Role::User);
+                    next_messages.insert(
+                        system_message_offset,
+                        ChatCompletionRequestMessageArgs::default()
+                            .content(msg.text)
+                            .role(role)
+                            .build()?,
+                    );
+                }
                 parent_message_id = msg.last_context.parent_message_id;
             }
         }
     }
-    let max_tokens = 1.max(max_response_tokens.min(max_model_tokens.saturating_sub(num_tokens)));
-    Ok((messages, max_tokens, num_tokens))
+    let max_tokens = max_response_tokens.min(max_model_tokens.saturating_sub(num_tokens));
+    Ok((messages, max_tokens, num_tokens, dropped_from))
 }
 
-fn get_message(id: &str) -> Option<ChatMessage> {
+/// Message `id`, which is a bare store key so anything but a uuid is refused.
+pub(crate) fn get_message(id: &str) -> Option<ChatMessage> {
+    if crate::history::check_id(id).is_err() {
+        return None;
+    }
     match crate::store::get(id) {
         Ok(Some(data)) => serde_json::from_slice::<ChatMessage>(&data)
             .map(|x| Some(x))
@@ -324,15 +1142,31 @@ fn get_message(id: &str) -> Option<ChatMessage> {
     }
 }
 
-fn put_message(msg: &ChatMessage) -> Result<()> {
+/// Store the answers to one request as siblings, the first one last so that it becomes the
+/// leaf of the conversation.
+fn put_answers(answers: &[ChatMessage]) {
+    answers.iter().rev().for_each(|x| {
+        put_message(x).ok();
+    });
+}
+
+pub(crate) fn put_message(msg: &ChatMessage) -> Result<()> {
     crate::store::put(&msg.id, serde_json::to_vec(&msg)?)?;
+    if let Some(parent) = &msg.last_context.parent_message_id {
+        crate::store::add_child(parent, &msg.id)?;
+    }
+    crate::history::add_message(msg)?;
     Ok(())
 }
 
 pub fn get_session() -> RespData<Value> {
-    resp_data(
-        json!({"auth": !get_env(AUTH_SECRET_KEY).is_empty(), "isChatGPTAPI": get_env("API_REVERSE_PROXY").is_empty()}),
-    )
+    resp_data(json!({
+        "auth": !get_env(AUTH_SECRET_KEY).is_empty(),
+        "isChatGPTAPI": !chatgpt::is_enabled(),
+        "model": get_model(),
+        "models": allowed_models(),
+        "tools": tools::enabled().iter().map(|x| x.schema()).collect::<Vec<_>>(),
+    }))
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
@@ -340,12 +1174,21 @@ pub struct TokenBody {
     token: String,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct IdBody {
+    pub id: String,
+}
+
+/// Stop generating the answer `id`, what was generated so far is kept.
+pub fn chat_abort(b: IdBody) -> RespData<Value> {
+    resp_data(json!({ "aborted": abort::abort(&b.id) }))
+}
+
 pub fn verify(b: TokenBody) -> Result<RespData<Value>> {
     if b.token.is_empty() {
         bail!("Secret key is empty");
     }
-    let auth = get_env(AUTH_SECRET_KEY);
-    if auth != b.token {
+    if !get_env_list(AUTH_SECRET_KEY).contains(&b.token) {
         bail!("Secret key is invalid");
     }
     Ok(resp_data(Value::Null))
@@ -354,6 +1197,174 @@ pub fn verify(b: TokenBody) -> Result<RespData<Value>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::history::path;
+    use once_cell::sync::Lazy;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::atomic::Ordering,
+    };
+
+    /// Held by the tests talking to the mock upstream, they share its env vars and abort
+    /// whatever is running.
+    static UPSTREAM: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::env::set_var("OPENAI_API_BASE_URL", url);
+        std::env::set_var("OPENAI_API_KEY", "sk-test-upstream");
+        std::env::set_var("ENABLED_TOOLS", "test_abort");
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                std::thread::spawn(move || serve(stream));
+            }
+        });
+        Default::default()
+    });
+
+    /// A stand-in for `/chat/completions`, answer `i` to a question is `answer {i} to
+    /// {question}`. Questions with `one` get a single answer whatever `n` says, `slow` ones
+    /// pause after the first words and with functions the first one is called.
+    fn serve(mut stream: TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut len = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    len = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; len];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+        let req: Value = serde_json::from_slice(&body).unwrap_or_default();
+        let messages = req["messages"].as_array().cloned().unwrap_or_default();
+        let question = messages
+            .iter()
+            .rev()
+            .find(|x| x["role"] == "user")
+            .and_then(|x| x["content"].as_str())
+            .unwrap_or_default()
+            .to_owned();
+        // a snapshot of the model asked for, as OpenAI names it
+        let model = format!("{}-0613", req["model"].as_str().unwrap_or_default());
+        let n = match question.contains("one") {
+            true => 1,
+            false => req["n"].as_u64().unwrap_or(1),
+        };
+        if req["stream"] == true {
+            let head =
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n";
+            stream.write_all(head.as_bytes()).ok();
+            let chunk = |i: u64, content: String, finish_reason: Option<&str>| {
+                let chunk = json!({
+                    "object": "chat.completion.chunk",
+                    "created": 0,
+                    "model": model,
+                    "choices": [{
+                        "index": i,
+                        "delta": {"role": "assistant", "content": content},
+                        "finish_reason": finish_reason,
+                    }],
+                });
+                format!("data: {chunk}\n\n")
+            };
+            for i in 0..n {
+                stream
+                    .write_all(chunk(i, format!("answer {i}"), None).as_bytes())
+                    .ok();
+            }
+            stream.flush().ok();
+            if question.contains("slow") {
+                std::thread::sleep(Duration::from_secs(2));
+            }
+            for i in 0..n {
+                let text = chunk(i, format!(" to {question}"), Some("stop"));
+                stream.write_all(text.as_bytes()).ok();
+            }
+            stream.write_all(b"data: [DONE]\n\n").ok();
+            return;
+        }
+        let asked = messages
+            .last()
+            .map(|x| x["role"] == "user")
+            .unwrap_or(false);
+        let choices: Vec<Value> = match req["functions"][0]["name"].as_str() {
+            Some(name) if asked => vec![json!({
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "function_call": {"name": name, "arguments": "{}"},
+                },
+                "finish_reason": "function_call",
+            })],
+            _ => (0..n)
+                .map(|i| {
+                    json!({
+                        "index": i,
+                        "message": {"role": "assistant", "content": format!("answer {i} to {question}")},
+                        "finish_reason": "stop",
+                    })
+                })
+                .collect(),
+        };
+        let body = json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "created": 0,
+            "model": model,
+            "choices": choices,
+            "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+        })
+        .to_string();
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).ok();
+        stream.write_all(body.as_bytes()).ok();
+    }
+
+    fn options(value: Value) -> RequestOptions {
+        serde_json::from_value(value).unwrap()
+    }
+
+    async fn ignore(_: ChatMessage) -> Result<()> {
+        Ok(())
+    }
+
+    fn path_ids(id: &str) -> Vec<String> {
+        path(id).unwrap().into_iter().map(|x| x.id).collect()
+    }
+
+    #[test]
+    fn test_max_response_tokens() {
+        assert_eq!(max_response_tokens("gpt-4-0613"), 2048);
+        assert_eq!(max_response_tokens("gpt-4-32k-0613"), 8192);
+        assert_eq!(max_response_tokens("gpt-3.5-turbo"), 1000);
+    }
+
+    #[test]
+    fn test_build_messages() {
+        let provider = get_provider().unwrap();
+        let opt = |words: usize| options(json!({"prompt": "hello ".repeat(words)}));
+        // the answer gets what the prompt leaves of the context
+        let (_, max_tokens, num_tokens, _) =
+            build_messages(provider.as_ref(), "gpt-4", opt(6500), 0).unwrap();
+        assert!(num_tokens > 6500);
+        assert_eq!(max_tokens, 8192 - num_tokens);
+        let (_, max_tokens, _, _) = build_messages(provider.as_ref(), "gpt-4", opt(10), 0).unwrap();
+        assert_eq!(max_tokens, 2048);
+        let err = build_messages(provider.as_ref(), "gpt-4", opt(8150), 0).unwrap_err();
+        let err = err.downcast_ref::<StructuredError>().unwrap();
+        assert_eq!(err.code, CONTEXT_LENGTH_EXCEEDED);
+    }
 
     #[test]
     fn test_serialize() {
@@ -366,4 +1377,217 @@ mod tests {
         let msg = serde_json::from_str::<ChatMessage>(&res);
         assert!(msg.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_abort_stream() {
+        let _upstream = UPSTREAM.lock().await;
+        let abort_first = |msg: ChatMessage| async move {
+            if !msg.delta.is_empty() {
+                abort::abort(&msg.id);
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        let opt = options(json!({"prompt": "slow question"}));
+        let answer = chat_process(opt, Some(abort_first)).await.unwrap().data;
+        assert_eq!(answer.finish_reason(), Some(CANCELLED));
+        assert!(answer.incomplete);
+        assert_eq!(answer.text, "answer 0");
+        let stored = get_message(&answer.id).unwrap();
+        assert_eq!(stored.finish_reason(), Some(CANCELLED));
+        assert_eq!(stored.text, "answer 0");
+        let path = path(&answer.id).unwrap();
+        assert_eq!(path.len(), 2);
+        assert_eq!(path[0].text, "slow question");
+    }
+
+    #[tokio::test]
+    async fn test_abort_slow_client() {
+        let _upstream = UPSTREAM.lock().await;
+        let blocked = std::sync::atomic::AtomicBool::new(false);
+        // the client stops reading after the first words, then the user aborts
+        let on_progress = |msg: ChatMessage| {
+            let block = !msg.delta.is_empty() && !blocked.swap(true, Ordering::SeqCst);
+            async move {
+                if block {
+                    abort::abort(&msg.id);
+                    futures::future::pending::<()>().await;
+                }
+                Ok::<_, anyhow::Error>(())
+            }
+        };
+        let opt = options(json!({"prompt": "question"}));
+        let answer = chat_process(opt, Some(on_progress)).await.unwrap().data;
+        assert_eq!(answer.finish_reason(), Some(CANCELLED));
+        assert_eq!(answer.text, "answer 0");
+        let stored = get_message(&answer.id).unwrap();
+        assert_eq!(stored.text, "answer 0");
+        assert!(stored.metadata().unwrap().completion_tokens > 0);
+    }
+
+    #[tokio::test]
+    async fn test_abort_tool_call() {
+        let _upstream = UPSTREAM.lock().await;
+        tools::register(Tool::new(
+            "test_abort",
+            "Stop the answers in flight",
+            json!({"type": "object", "properties": {}}),
+            |_| async {
+                abort::running().iter().for_each(|x| {
+                    abort::abort(x);
+                });
+                Ok("stopped".to_owned())
+            },
+        ));
+        let opt = options(json!({"prompt": "question", "tools": ["test_abort"]}));
+        let answer = chat_process(opt, Some(ignore)).await.unwrap().data;
+        assert_eq!(answer.finish_reason(), Some(CANCELLED));
+        // the answer hangs below the stored output of the call
+        let path = path(&answer.id).unwrap();
+        assert_eq!(path.len(), 4);
+        assert!(path[1].function_call.is_some());
+        assert_eq!(path[2].text, "stopped");
+        assert_eq!(
+            answer.last_context.parent_message_id,
+            Some(path[2].id.clone())
+        );
+        assert_eq!(path_ids(&path[2].id), path_ids(&answer.id)[..3]);
+    }
+
+    fn sorted(mut ids: Vec<String>) -> Vec<String> {
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_regenerate() {
+        let _upstream = UPSTREAM.lock().await;
+        let opt = options(json!({"prompt": "question"}));
+        let a1 = chat_process(opt, Some(ignore)).await.unwrap().data;
+        let q = a1.last_context.parent_message_id.clone().unwrap();
+        let conversation = a1.last_context.conversation_id.clone().unwrap();
+        let opt: RegenerateOptions = serde_json::from_value(json!({"id": a1.id})).unwrap();
+        let a2 = chat_regenerate(opt, Some(ignore)).await.unwrap().data;
+        assert_eq!(a2.text, "answer 0 to question");
+        assert_eq!(a2.model.as_deref(), Some("gpt-3.5-turbo-0613"));
+        assert_eq!(
+            sorted(crate::store::children(&q).unwrap()),
+            sorted(vec![a1.id.clone(), a2.id.clone()])
+        );
+        assert_eq!(path_ids(&a2.id), vec![q.clone(), a2.id.clone()]);
+        assert_eq!(path_ids(&a1.id), vec![q, a1.id.clone()]);
+        let c = crate::history::get_conversation(&conversation).unwrap();
+        assert_eq!(c.leaf_id, a2.id);
+        assert_eq!(c.message_count, 3);
+        // only answers can be regenerated
+        let opt: RegenerateOptions =
+            serde_json::from_value(json!({"id": a1.last_context.parent_message_id})).unwrap();
+        assert!(chat_regenerate(opt, Some(ignore)).await.is_err());
+    }
+
+    fn sibling_ids(id: &str) -> Vec<String> {
+        let siblings = crate::history::message_siblings(IdBody { id: id.to_owned() });
+        let siblings: Vec<ChatMessage> = serde_json::from_value(siblings.unwrap().data).unwrap();
+        sorted(siblings.into_iter().map(|x| x.id).collect())
+    }
+
+    #[tokio::test]
+    async fn test_edit() {
+        let _upstream = UPSTREAM.lock().await;
+        let a1 = chat_process(options(json!({"prompt": "first"})), Some(ignore))
+            .await
+            .unwrap()
+            .data;
+        let q1 = a1.last_context.parent_message_id.clone().unwrap();
+        let last_context = json!({
+            "conversationId": a1.last_context.conversation_id,
+            "parentMessageId": a1.id,
+        });
+        let opt = options(json!({"prompt": "second", "lastContext": last_context}));
+        let a2 = chat_process(opt, Some(ignore)).await.unwrap().data;
+        let q2 = a2.last_context.parent_message_id.clone().unwrap();
+
+        let opt = options(json!({"prompt": "edited", "editMessageId": q2}));
+        let b2 = chat_process(opt, Some(ignore)).await.unwrap().data;
+        assert_eq!(b2.text, "answer 0 to edited");
+        assert_eq!(get_message(&b2.id).unwrap().model, b2.model);
+        let p2 = b2.last_context.parent_message_id.clone().unwrap();
+        assert_eq!(sibling_ids(&q2), sorted(vec![q2.clone(), p2.clone()]));
+        assert_eq!(
+            path_ids(&b2.id),
+            vec![q1.clone(), a1.id.clone(), p2, b2.id.clone()]
+        );
+        // the original branch is left as it is
+        assert_eq!(
+            path_ids(&a2.id),
+            vec![q1.clone(), a1.id.clone(), q2, a2.id.clone()]
+        );
+
+        // first questions have no parent, their siblings are the other roots
+        let opt = options(json!({"prompt": "edited first", "editMessageId": q1}));
+        let b1 = chat_process(opt, Some(ignore)).await.unwrap().data;
+        let p1 = b1.last_context.parent_message_id.clone().unwrap();
+        assert_eq!(path_ids(&b1.id), vec![p1.clone(), b1.id.clone()]);
+        assert_eq!(sibling_ids(&q1), sorted(vec![q1, p1]));
+
+        let opt = options(json!({"prompt": "edited", "editMessageId": a1.id}));
+        assert!(chat_process(opt, Some(ignore)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_context() {
+        let _upstream = UPSTREAM.lock().await;
+        let last_context = json!({"conversationId": "totals/2099-01/user/x"});
+        let opt = options(json!({"prompt": "question", "lastContext": last_context}));
+        assert!(chat_process(opt, Some(ignore)).await.is_err());
+        let last_context = json!({"parentMessageId": "children/x"});
+        let opt = options(json!({"prompt": "question", "lastContext": last_context}));
+        assert!(chat_process(opt, Some(ignore)).await.is_err());
+        assert!(crate::store::scan_prefix("totals/2099-01")
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Children of the question of `answer`, in the order of `index`.
+    fn stored_answers(answer: &ChatMessage) -> Vec<ChatMessage> {
+        let q = answer.last_context.parent_message_id.as_ref().unwrap();
+        let mut answers: Vec<ChatMessage> = crate::store::children(q)
+            .unwrap()
+            .iter()
+            .map(|x| get_message(x).unwrap())
+            .collect();
+        answers.sort_by_key(|x| x.index);
+        answers
+    }
+
+    #[tokio::test]
+    async fn test_several_answers() {
+        let _upstream = UPSTREAM.lock().await;
+        let opt = options(json!({"prompt": "question", "n": 3, "temperature": 1.5}));
+        let answer = chat_process(opt, Some(ignore)).await.unwrap().data;
+        let answers = stored_answers(&answer);
+        assert_eq!(answers.len(), 3);
+        for (i, x) in answers.iter().enumerate() {
+            assert_eq!(x.index, Some(i as u32));
+            assert_eq!(x.text, format!("answer {i} to question"));
+        }
+        assert_eq!(answer.id, answers[0].id);
+        let conversation = answer.last_context.conversation_id.clone().unwrap();
+        let c = crate::history::get_conversation(&conversation).unwrap();
+        assert_eq!(c.leaf_id, answer.id);
+        // n is per request, the rest is kept for the conversation
+        let stored = sampling::get(&conversation);
+        assert_eq!(stored.n, None);
+        assert_eq!(stored.temperature, Some(1.5));
+
+        // answers upstream never filled are dropped, streamed or not
+        let opt = options(json!({"prompt": "one question", "n": 3}));
+        let answer = chat_process(opt, Some(ignore)).await.unwrap().data;
+        assert_eq!(stored_answers(&answer).len(), 1);
+        let opt = options(json!({"prompt": "one question", "n": 3}));
+        let quiet = None::<fn(ChatMessage) -> std::future::Ready<Result<()>>>;
+        let answer = chat_process(opt, quiet).await.unwrap().data;
+        assert_eq!(answer.text, "answer 0 to one question");
+        assert_eq!(answer.model.as_deref(), Some("gpt-3.5-turbo-0613"));
+        assert_eq!(stored_answers(&answer).len(), 1);
+    }
 }
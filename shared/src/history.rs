@@ -0,0 +1,356 @@
+use crate::{
+    gpt::{get_message, ChatMessage, IdBody},
+    now_ms, resp_data, store, RespData, Result,
+};
+use anyhow::{bail, Context};
+use async_openai::types::Role;
+use serde_json::{json, Value};
+
+/// Ids from clients end up in store keys, only uuids are taken.
+pub(crate) fn check_id(id: &str) -> Result<()> {
+    if uuid::Uuid::parse_str(id).is_err() {
+        bail!("Invalid id {id}");
+    }
+    Ok(())
+}
+
+fn load(ids: &[String]) -> Vec<ChatMessage> {
+    ids.iter().filter_map(|id| get_message(id)).collect()
+}
+
+/// Replies to message `id`, oldest first.
+pub fn message_children(b: IdBody) -> Result<RespData<Value>> {
+    check_id(&b.id)?;
+    Ok(resp_data(json!(load(&store::children(&b.id)?))))
+}
+
+/// Message `id` and the alternatives to it, oldest first.
+pub fn message_siblings(b: IdBody) -> Result<RespData<Value>> {
+    let msg = get_message(&b.id).with_context(|| format!("Message {} not found", b.id))?;
+    let siblings = match (
+        &msg.last_context.parent_message_id,
+        &msg.last_context.conversation_id,
+    ) {
+        (Some(parent), _) => load(&store::children(parent)?),
+        // first questions of a conversation, edited ones included
+        (None, Some(conversation)) => {
+            let mut roots: Vec<_> = load(&message_ids(conversation)?)
+                .into_iter()
+                .filter(|x| x.last_context.parent_message_id.is_none())
+                .collect();
+            roots.sort_by_key(|x| x.created_at.unwrap_or(0));
+            roots
+        }
+        (None, None) => vec![],
+    };
+    // messages stored before the indexes existed
+    if !siblings.iter().any(|x| x.id == b.id) {
+        return Ok(resp_data(json!([msg])));
+    }
+    Ok(resp_data(json!(siblings)))
+}
+
+/// Messages from the root of the conversation down to `id`.
+pub fn message_path(b: IdBody) -> Result<RespData<Value>> {
+    Ok(resp_data(json!(path(&b.id)?)))
+}
+
+pub(crate) fn path(id: &str) -> Result<Vec<ChatMessage>> {
+    let mut path = vec![get_message(id).with_context(|| format!("Message {id} not found"))?];
+    while let Some(parent) = path.last().unwrap().last_context.parent_message_id.clone() {
+        match get_message(&parent) {
+            Some(msg) => path.push(msg),
+            None => break,
+        }
+    }
+    path.reverse();
+    Ok(path)
+}
+
+/// Longest title taken from the first question of a conversation.
+const TITLE_CHARS: usize = 50;
+
+/// Index entry of a conversation, so history lives on the server rather than in the client.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Conversation {
+    pub id: String,
+    pub title: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub message_count: usize,
+    /// The latest message, where the conversation goes on by default.
+    pub leaf_id: String,
+}
+
+// `conversation/{id}` -> Conversation, `messages/{conversation}/{message}` -> ()
+#[inline]
+pub(crate) fn conversation_key(id: &str) -> String {
+    format!("conversation/{id}")
+}
+
+#[inline]
+pub(crate) fn message_key(conversation: &str, id: &str) -> String {
+    format!("messages/{conversation}/{id}")
+}
+
+/// Account for a newly stored message in its conversation.
+pub(crate) fn add_message(msg: &ChatMessage) -> Result<()> {
+    let conversation = match &msg.last_context.conversation_id {
+        Some(x) if !x.is_empty() => x,
+        _ => return Ok(()),
+    };
+    let key = message_key(conversation, &msg.id);
+    let is_new = store::get(&key)?.is_none();
+    store::put(&key, b"")?;
+    let now = msg.created_at.unwrap_or_else(now_ms);
+    store::update(conversation_key(conversation), |v| {
+        let mut c = match v {
+            Some(v) => serde_json::from_slice(&v)?,
+            None => Conversation {
+                id: conversation.to_owned(),
+                created_at: now,
+                ..Default::default()
+            },
+        };
+        if c.title.is_empty() && !matches!(msg.role, Some(Role::Assistant)) && !msg.is_tool_step() {
+            c.title = msg
+                .text
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .chars()
+                .take(TITLE_CHARS)
+                .collect();
+        }
+        if is_new {
+            c.message_count += 1;
+        }
+        c.updated_at = now;
+        c.leaf_id = msg.id.clone();
+        Ok(serde_json::to_vec(&c)?)
+    })
+}
+
+pub(crate) fn get_conversation(id: &str) -> Result<Conversation> {
+    check_id(id)?;
+    let data = store::get(conversation_key(id))?
+        .with_context(|| format!("Conversation {id} not found"))?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Ids of the messages stored in conversation `id`.
+pub(crate) fn message_ids(id: &str) -> Result<Vec<String>> {
+    let prefix = message_key(id, "");
+    Ok(store::scan_prefix(&prefix)?
+        .into_iter()
+        .map(|(k, _)| String::from_utf8_lossy(&k[prefix.len()..]).into_owned())
+        .collect())
+}
+
+/// Conversations, the most recently updated first.
+pub fn conversations() -> Result<RespData<Value>> {
+    let mut list: Vec<Conversation> = store::scan_prefix(conversation_key(""))?
+        .into_iter()
+        .filter_map(|(_, v)| serde_json::from_slice(&v).ok())
+        .collect();
+    list.sort_by_key(|x| std::cmp::Reverse(x.updated_at));
+    Ok(resp_data(json!(list)))
+}
+
+/// A conversation with all of its messages, oldest first.
+pub fn conversation(b: IdBody) -> Result<RespData<Value>> {
+    let conversation = get_conversation(&b.id)?;
+    let mut messages = load(&message_ids(&b.id)?);
+    messages.sort_by_key(|x| x.created_at.unwrap_or(0));
+    Ok(resp_data(json!({
+        "conversation": conversation,
+        "messages": messages,
+        "sampling": crate::sampling::get(&b.id),
+    })))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct RenameBody {
+    pub id: String,
+    pub title: String,
+}
+
+pub fn conversation_rename(b: RenameBody) -> Result<RespData<Value>> {
+    get_conversation(&b.id)?;
+    store::update(conversation_key(&b.id), |v| {
+        let mut c: Conversation = serde_json::from_slice(&v.unwrap_or_default())?;
+        c.title = b.title.trim().to_owned();
+        Ok(serde_json::to_vec(&c)?)
+    })?;
+    Ok(resp_data(json!(get_conversation(&b.id)?)))
+}
+
+/// Continue the conversation of message `id` from there, e.g. with one of several answers.
+/// The leaf becomes its latest reply, or the latest reply to that and so on.
+pub fn conversation_select(b: IdBody) -> Result<RespData<Value>> {
+    let msg = get_message(&b.id).with_context(|| format!("Message {} not found", b.id))?;
+    let conversation = msg
+        .last_context
+        .conversation_id
+        .with_context(|| format!("Message {} is in no conversation", b.id))?;
+    get_conversation(&conversation)?;
+    let mut leaf_id = b.id;
+    while let Some(child) = store::children(&leaf_id)?.pop() {
+        leaf_id = child;
+    }
+    store::update(conversation_key(&conversation), |v| {
+        let mut c: Conversation = serde_json::from_slice(&v.unwrap_or_default())?;
+        c.leaf_id = leaf_id;
+        Ok(serde_json::to_vec(&c)?)
+    })?;
+    Ok(resp_data(json!(get_conversation(&conversation)?)))
+}
+
+/// Delete a conversation and every message in it.
+pub fn conversation_delete(b: IdBody) -> Result<RespData<Value>> {
+    get_conversation(&b.id)?;
+    let ids = message_ids(&b.id)?;
+    let deleted = purge(&ids)?;
+    for id in &ids {
+        store::delete(message_key(&b.id, id))?;
+    }
+    store::delete(conversation_key(&b.id))?;
+    store::delete(crate::summary::summary_key(&b.id))?;
+    store::delete(crate::sampling::sampling_key(&b.id))?;
+    Ok(resp_data(json!({ "deleted": deleted })))
+}
+
+/// Delete message `id` together with every reply below it.
+pub fn message_delete(b: IdBody) -> Result<RespData<Value>> {
+    let msg = get_message(&b.id).with_context(|| format!("Message {} not found", b.id))?;
+    let mut ids = vec![b.id];
+    let mut i = 0;
+    while i < ids.len() {
+        let children = store::children(&ids[i])?;
+        ids.extend(children);
+        i += 1;
+    }
+    let deleted = purge(&ids)?;
+    if let Some(conversation) = &msg.last_context.conversation_id {
+        refresh_conversation(conversation)?;
+    }
+    Ok(resp_data(json!({ "deleted": deleted })))
+}
+
+/// Remove messages from the store and the indexes, returns how many were there.
+fn purge(ids: &[String]) -> Result<usize> {
+    let mut deleted = 0;
+    for id in ids {
+        if let Some(msg) = get_message(id) {
+            if let Some(parent) = &msg.last_context.parent_message_id {
+                store::remove_child(parent, id)?;
+            }
+            if let Some(conversation) = &msg.last_context.conversation_id {
+                store::delete(message_key(conversation, id))?;
+                crate::summary::forget(conversation, id)?;
+            }
+            deleted += 1;
+        }
+        store::delete(id)?;
+    }
+    Ok(deleted)
+}
+
+/// Recount a conversation after messages left it, dropping it once empty.
+fn refresh_conversation(id: &str) -> Result<()> {
+    let mut messages = load(&message_ids(id)?);
+    if messages.is_empty() {
+        return store::delete(conversation_key(id));
+    }
+    // nothing replies to the latest message, so it is a leaf
+    messages.sort_by_key(|x| x.created_at.unwrap_or(0));
+    let latest = messages.last().unwrap();
+    store::update(conversation_key(id), |v| {
+        let mut c: Conversation = serde_json::from_slice(&v.unwrap_or_default())?;
+        c.message_count = messages.len();
+        if !messages.iter().any(|x| x.id == c.leaf_id) {
+            c.leaf_id = latest.id.clone();
+        }
+        Ok(serde_json::to_vec(&c)?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gpt::put_message, gpt::RequestContext, summary::summary_key};
+
+    fn put(conversation: &str, parent: Option<&str>, role: Role) -> String {
+        let msg = ChatMessage {
+            id: uuid::Uuid::new_v4().to_string(),
+            role: Some(role),
+            text: "hello".to_owned(),
+            created_at: Some(now_ms()),
+            last_context: RequestContext {
+                conversation_id: Some(conversation.to_owned()),
+                parent_message_id: parent.map(|x| x.to_owned()),
+            },
+            ..Default::default()
+        };
+        put_message(&msg).unwrap();
+        msg.id
+    }
+
+    fn put_summary(conversation: &str, upto: &str) {
+        let cached = json!({"upto": upto, "text": "summary", "updatedAt": 0});
+        store::put(summary_key(conversation), cached.to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_message_delete() {
+        let conversation = uuid::Uuid::new_v4().to_string();
+        let q1 = put(&conversation, None, Role::User);
+        let a1 = put(&conversation, Some(&q1), Role::Assistant);
+        let q2 = put(&conversation, Some(&a1), Role::User);
+        let a2 = put(&conversation, Some(&q2), Role::Assistant);
+        let b1 = put(&conversation, Some(&q1), Role::Assistant);
+        // both added within the same millisecond maybe, so in any order
+        let mut children = store::children(&q1).unwrap();
+        children.sort();
+        let mut expected = vec![a1.clone(), b1.clone()];
+        expected.sort();
+        assert_eq!(children, expected);
+        assert_eq!(path(&a2).unwrap().len(), 4);
+
+        // the summary covers q2, which goes with a1
+        put_summary(&conversation, &q2);
+        let deleted = message_delete(IdBody { id: a1.clone() }).unwrap().data;
+        assert_eq!(deleted["deleted"], 3);
+        assert_eq!(store::children(&q1).unwrap(), vec![b1.clone()]);
+        assert!(store::children(&a1).unwrap().is_empty());
+        assert!(path(&a2).is_err());
+        let ids: Vec<String> = path(&b1).unwrap().into_iter().map(|x| x.id).collect();
+        assert_eq!(ids, vec![q1.clone(), b1.clone()]);
+        let c = get_conversation(&conversation).unwrap();
+        assert_eq!(c.message_count, 2);
+        assert_eq!(c.leaf_id, b1);
+        assert!(store::get(summary_key(&conversation)).unwrap().is_none());
+
+        // a summary of what is left stays
+        put_summary(&conversation, &q1);
+        message_delete(IdBody { id: b1 }).unwrap();
+        assert!(store::get(summary_key(&conversation)).unwrap().is_some());
+        conversation_delete(IdBody {
+            id: conversation.clone(),
+        })
+        .unwrap();
+        assert!(get_conversation(&conversation).is_err());
+        assert!(store::get(summary_key(&conversation)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_check_id() {
+        assert!(message_delete(IdBody {
+            id: "children/".to_owned()
+        })
+        .is_err());
+        assert!(message_children(IdBody { id: "".to_owned() }).is_err());
+        assert!(check_id(&uuid::Uuid::new_v4().to_string()).is_ok());
+    }
+}
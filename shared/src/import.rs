@@ -0,0 +1,334 @@
+use crate::{
+    gpt::{ChatMessage, RequestContext},
+    history::{self, conversation_key, message_key, Conversation},
+    resp_data, store, RespData, Result,
+};
+use anyhow::bail;
+use async_openai::types::Role;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// https://help.openai.com/en/articles/7260999-how-do-i-export-my-chatgpt-history-and-data
+#[derive(serde::Deserialize, Debug, Default)]
+struct Exported {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    conversation_id: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    create_time: Option<f64>,
+    #[serde(default)]
+    update_time: Option<f64>,
+    #[serde(default)]
+    mapping: HashMap<String, Node>,
+    #[serde(default)]
+    current_node: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+struct Node {
+    #[serde(default)]
+    message: Option<Value>,
+    #[serde(default)]
+    parent: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct ImportBody {
+    /// Content of `conversations.json`.
+    pub conversations: Vec<Value>,
+    /// Only report what would be imported.
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: bool,
+}
+
+/// Seconds with a fraction, as in the export, to milliseconds.
+#[inline]
+fn to_ms(time: Option<f64>) -> Option<u64> {
+    time.filter(|x| *x > 0.0).map(|x| (x * 1000.0) as u64)
+}
+
+/// The message of a node, `None` for nodes not worth keeping (system prompts, tools, images).
+fn convert(id: &str, message: &Value) -> Option<ChatMessage> {
+    let role = match message["author"]["role"].as_str()? {
+        "user" => Role::User,
+        "assistant" => Role::Assistant,
+        _ => return None,
+    };
+    let content = &message["content"];
+    let text = match content["parts"].as_array() {
+        Some(parts) => parts
+            .iter()
+            .filter_map(|x| x.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => content["text"].as_str().unwrap_or_default().to_owned(),
+    };
+    if text.trim().is_empty() {
+        return None;
+    }
+    Some(ChatMessage {
+        role: Some(role),
+        id: id.to_owned(),
+        text,
+        created_at: to_ms(message["create_time"].as_f64()),
+        model: message["metadata"]["model_slug"]
+            .as_str()
+            .map(|x| x.to_owned()),
+        ..Default::default()
+    })
+}
+
+/// `id` if it is a uuid, otherwise one derived from it. Ids end up in store keys so nothing
+/// else is taken, and the same file imported again still maps to the same ids.
+fn to_uuid(id: &str) -> String {
+    if history::check_id(id).is_ok() {
+        return id.to_owned();
+    }
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&Sha256::digest(id)[..16]);
+    uuid::Builder::from_random_bytes(bytes)
+        .into_uuid()
+        .to_string()
+}
+
+/// Following the parents of `node` comes back around instead of ending at a root.
+fn has_cycle(mapping: &HashMap<String, Node>, node: &str) -> bool {
+    let mut node = Some(node);
+    for _ in 0..=mapping.len() {
+        match node.and_then(|x| mapping.get(x)) {
+            Some(x) => node = x.parent.as_deref(),
+            None => return false,
+        }
+    }
+    true
+}
+
+/// The node itself if kept, otherwise its closest kept ancestor.
+fn resolve<'a>(
+    mapping: &'a HashMap<String, Node>,
+    kept: &HashMap<&str, ChatMessage>,
+    mut node: Option<&'a str>,
+) -> Option<String> {
+    for _ in 0..=mapping.len() {
+        let id = node?;
+        if kept.contains_key(id) {
+            return Some(id.to_owned());
+        }
+        node = mapping.get(id)?.parent.as_deref();
+    }
+    None
+}
+
+/// The conversation with its kept messages, parents re-linked past the dropped nodes and
+/// ids which are not uuids replaced.
+fn convert_conversation(exported: Exported) -> Result<(Conversation, Vec<ChatMessage>)> {
+    if let Some(node) = exported
+        .mapping
+        .keys()
+        .find(|x| has_cycle(&exported.mapping, x))
+    {
+        bail!("Node {node} is its own ancestor");
+    }
+    let id = match exported.id.or(exported.conversation_id) {
+        Some(id) => to_uuid(&id),
+        None => uuid::Uuid::new_v4().to_string(),
+    };
+    // node ids are only unique within their conversation
+    let message_id = |node: String| match history::check_id(&node) {
+        Ok(()) => node,
+        Err(_) => to_uuid(&format!("{id}/{node}")),
+    };
+    let created_at = to_ms(exported.create_time).unwrap_or_default();
+    let kept: HashMap<&str, ChatMessage> = exported
+        .mapping
+        .iter()
+        .filter_map(|(id, node)| Some((id.as_str(), convert(id, node.message.as_ref()?)?)))
+        .collect();
+    let resolve = |node: Option<&str>| resolve(&exported.mapping, &kept, node);
+    let mut messages: Vec<ChatMessage> = kept
+        .iter()
+        .map(|(node, msg)| {
+            let parent = exported.mapping[*node].parent.as_deref();
+            ChatMessage {
+                id: message_id(msg.id.clone()),
+                created_at: msg.created_at.or(Some(created_at)),
+                last_context: RequestContext {
+                    conversation_id: Some(id.clone()),
+                    parent_message_id: resolve(parent).map(message_id),
+                },
+                ..msg.clone()
+            }
+        })
+        .collect();
+    messages.sort_by_key(|x| x.created_at.unwrap_or(0));
+    let leaf_id = resolve(exported.current_node.as_deref())
+        .map(message_id)
+        .or_else(|| messages.last().map(|x| x.id.clone()))
+        .unwrap_or_default();
+    let conversation = Conversation {
+        id,
+        title: exported.title.unwrap_or_default(),
+        created_at,
+        updated_at: to_ms(exported.update_time).unwrap_or(created_at),
+        message_count: messages.len(),
+        leaf_id,
+    };
+    Ok((conversation, messages))
+}
+
+/// Bring in the official ChatGPT data export, conversations already here are skipped.
+pub fn import(b: ImportBody) -> Result<RespData<Value>> {
+    let (mut conversations, mut messages, mut skipped) = (0, 0, 0);
+    let mut items = vec![];
+    for value in b.conversations {
+        let exported: Exported = match serde_json::from_value(value) {
+            Ok(x) => x,
+            Err(err) => {
+                log::warn!("Skip unreadable conversation: {err}");
+                skipped += 1;
+                continue;
+            }
+        };
+        let (conversation, list) = match convert_conversation(exported) {
+            Ok(x) => x,
+            Err(err) => {
+                log::warn!("Skip invalid conversation: {err}");
+                skipped += 1;
+                continue;
+            }
+        };
+        if list.is_empty() || history::get_conversation(&conversation.id).is_ok() {
+            skipped += 1;
+            continue;
+        }
+        conversations += 1;
+        messages += list.len();
+        if b.dry_run {
+            continue;
+        }
+        for msg in &list {
+            items.push((msg.id.clone().into(), serde_json::to_vec(msg)?));
+            if let Some(parent) = &msg.last_context.parent_message_id {
+                let added = msg.created_at.unwrap_or_default().to_be_bytes();
+                items.push((store::child_key(parent, &msg.id).into(), added.to_vec()));
+            }
+            items.push((message_key(&conversation.id, &msg.id).into(), vec![]));
+        }
+        items.push((
+            conversation_key(&conversation.id).into(),
+            serde_json::to_vec(&conversation)?,
+        ));
+    }
+    if !b.dry_run {
+        store::put_all(items)?;
+    }
+    Ok(resp_data(json!({
+        "dryRun": b.dry_run,
+        "conversations": conversations,
+        "messages": messages,
+        "skipped": skipped,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_conversation() {
+        let exported: Exported = serde_json::from_value(json!({
+            "id": "c1",
+            "title": "Hello",
+            "create_time": 1.5,
+            "current_node": "a1",
+            "mapping": {
+                "root": {"message": null, "parent": null},
+                "sys": {
+                    "message": {"author": {"role": "system"}, "content": {"parts": [""]}},
+                    "parent": "root"
+                },
+                "u1": {
+                    "message": {"author": {"role": "user"}, "create_time": 2.0, "content": {"parts": ["Hi"]}},
+                    "parent": "sys"
+                },
+                "a1": {
+                    "message": {
+                        "author": {"role": "assistant"},
+                        "create_time": 3.0,
+                        "content": {"parts": ["Hello!"]},
+                        "metadata": {"model_slug": "gpt-4"}
+                    },
+                    "parent": "u1"
+                }
+            }
+        }))
+        .unwrap();
+        let (conversation, messages) = convert_conversation(exported).unwrap();
+        assert_eq!(conversation.id, to_uuid("c1"));
+        assert_eq!(conversation.created_at, 1500);
+        assert_eq!(conversation.leaf_id, messages[1].id);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].id, to_uuid(&format!("{}/u1", conversation.id)));
+        assert_eq!(messages[0].last_context.parent_message_id, None);
+        assert_eq!(
+            messages[1].last_context.parent_message_id.as_ref(),
+            Some(&messages[0].id)
+        );
+        assert_eq!(messages[1].model.as_deref(), Some("gpt-4"));
+    }
+
+    #[test]
+    fn test_import() {
+        let user =
+            json!({"author": {"role": "user"}, "create_time": 2.0, "content": {"parts": ["Hi"]}});
+        let answer = json!({"author": {"role": "assistant"}, "create_time": 3.0, "content": {"parts": ["Hello!"]}});
+        let kept = uuid::Uuid::new_v4().to_string();
+        let body = ImportBody {
+            conversations: vec![
+                json!({
+                    "id": "c9",
+                    "current_node": kept,
+                    "mapping": {
+                        "totals/2099-01/user/x": {"message": user, "parent": null},
+                        kept.clone(): {"message": answer, "parent": "totals/2099-01/user/x"},
+                    }
+                }),
+                json!({
+                    "id": "c10",
+                    "mapping": {
+                        "u1": {"message": user, "parent": "a1"},
+                        "a1": {"message": answer, "parent": "u1"},
+                    }
+                }),
+            ],
+            dry_run: false,
+        };
+        let imported = import(body.clone()).unwrap().data;
+        assert_eq!(imported["conversations"], 1);
+        assert_eq!(imported["skipped"], 1);
+        assert!(store::get("totals/2099-01/user/x").unwrap().is_none());
+
+        let id = to_uuid("c9");
+        let data = history::conversation(crate::gpt::IdBody { id })
+            .unwrap()
+            .data;
+        assert_eq!(data["conversation"]["leafId"], kept.as_str());
+        let messages: Vec<ChatMessage> = serde_json::from_value(data["messages"].clone()).unwrap();
+        assert_eq!(messages.len(), 2);
+        let path: Vec<String> = history::path(&kept)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.text)
+            .collect();
+        assert_eq!(path, vec!["Hi", "Hello!"]);
+
+        // already here
+        let imported = import(body).unwrap().data;
+        assert_eq!(imported["conversations"], 0);
+        assert_eq!(imported["skipped"], 2);
+    }
+}
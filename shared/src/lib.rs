@@ -1,10 +1,21 @@
+pub mod abort;
+pub mod budget;
+pub mod catalog;
+pub mod export;
 pub mod gpt;
+pub mod history;
+pub mod import;
 pub mod store;
+pub mod summary;
+pub mod tools;
+pub mod usage;
 pub use anyhow;
 pub use log;
 pub use serde;
 pub use serde_json;
 pub mod network;
+pub mod provider;
+pub mod sampling;
 pub use tokio;
 pub use once_cell;
 pub use uuid;
@@ -25,6 +36,27 @@ pub fn get_env_or<T: Into<String>>(key: &str, default_value: T) -> String {
     }
 }
 
+/// Parse `a,b,c` style variables.
+pub fn get_env_list(key: &str) -> Vec<String> {
+    get_env(key)
+        .split(',')
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Parse `a=1,b=2` style variables, e.g. per-model settings.
+pub fn get_env_map(key: &str) -> Vec<(String, String)> {
+    get_env(key)
+        .split(',')
+        .filter_map(|x| {
+            let (k, v) = x.split_once('=')?;
+            Some((k.trim().to_owned(), v.trim().to_owned()))
+        })
+        .filter(|(k, _)| !k.is_empty())
+        .collect()
+}
+
 pub fn init_env() {
     use env_logger::{Env, DEFAULT_FILTER_ENV};
     env_logger::init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
@@ -42,7 +74,7 @@ pub fn init_env() {
 
 #[derive(serde::Serialize)]
 pub struct RespData<T: serde::Serialize> {
-    data: T,
+    pub data: T,
     status: &'static str,
 }
 
@@ -55,7 +87,76 @@ pub fn resp_data<T: serde::Serialize>(data: T) -> RespData<T> {
 
 pub type RespValue = RespData<serde_json::Value>;
 
+/// An error clients can act on: `code` is stable and `details` machine readable.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct StructuredError {
+    pub code: &'static str,
+    pub message: String,
+    pub details: serde_json::Value,
+}
+
+impl std::fmt::Display for StructuredError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for StructuredError {}
+
 #[inline]
 pub fn timeout<T: std::future::Future>(ms: u64, future: T) -> tokio::time::Timeout<T> {
     tokio::time::timeout(std::time::Duration::from_millis(ms), future)
 }
+
+/// Milliseconds since the unix epoch.
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+/// `YYYY-MM-DD` (UTC) of a timestamp in milliseconds.
+pub fn format_date(ms: u64) -> String {
+    let (y, m, d) = civil_from_days((ms / 86_400_000) as i64);
+    format!("{y:04}-{m:02}-{d:02}")
+}
+
+/// `YYYY-MM-DD HH:MM:SS` (UTC) of a timestamp in milliseconds.
+pub fn format_datetime(ms: u64) -> String {
+    let secs = ms / 1000 % 86_400;
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        format_date(ms),
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_format_date() {
+        assert_eq!(super::format_date(0), "1970-01-01");
+        assert_eq!(super::format_date(951_782_400_000), "2000-02-29");
+        assert_eq!(
+            super::format_datetime(1_683_000_000_123),
+            "2023-05-02 04:00:00"
+        );
+    }
+}
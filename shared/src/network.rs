@@ -1,4 +1,7 @@
 use crate::get_env;
+use anyhow::bail;
+use reqwest::Url;
+use std::net::{IpAddr, SocketAddr};
 
 pub fn build_proxy_client() -> Option<reqwest::Client> {
     match build_proxy() {
@@ -69,6 +72,23 @@ fn get_auth(url: &str) -> Option<(String, String)> {
     None
 }
 
+/// Most bytes of a response body `fetch_public` reads, the rest is dropped.
+const MAX_BODY_BYTES: usize = 1 << 20;
+/// Most redirects `fetch_public` follows.
+const MAX_REDIRECTS: usize = 5;
+
+async fn read_body(mut resp: reqwest::Response) -> crate::Result<String> {
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_BODY_BYTES {
+            body.truncate(MAX_BODY_BYTES);
+            break;
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
 pub async fn fetch(url: &str) -> crate::Result<String> {
     Ok(build_proxy_client()
         .unwrap_or(reqwest::Client::new())
@@ -79,6 +99,84 @@ pub async fn fetch(url: &str) -> crate::Result<String> {
         .await?)
 }
 
+/// Whether `ip` is reachable from the internet, i.e. not loopback, private, link-local,
+/// shared, multicast or otherwise reserved.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || a >= 240
+                // 100.64.0.0/10, carrier-grade NAT
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // fc00::/7 unique local, fe80::/10 link-local
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// The address to connect to for `url`, refusing hosts that resolve to the local network.
+async fn resolve_public(url: &Url) -> crate::Result<SocketAddr> {
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host = url.host_str().unwrap_or_default();
+    let addrs: Vec<SocketAddr> = match host.trim_matches(|x| x == '[' || x == ']').parse() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) if host.is_empty() => bail!("No host in {url}"),
+        Err(_) => tokio::net::lookup_host((host, port)).await?.collect(),
+    };
+    match addrs.first() {
+        Some(_) if addrs.iter().any(|x| !is_public(x.ip())) => {
+            bail!("Refuse to fetch {url}, it is not a public address")
+        }
+        Some(addr) => Ok(*addr),
+        None => bail!("Cannot resolve {url}"),
+    }
+}
+
+/// `fetch` for urls from untrusted input such as the model: only http(s) to public
+/// addresses, checked again on every redirect.
+pub async fn fetch_public(url: &str) -> crate::Result<String> {
+    let mut url = Url::parse(url)?;
+    for _ in 0..=MAX_REDIRECTS {
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("Invalid url {url}");
+        }
+        let addr = resolve_public(&url).await?;
+        let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+        if let Ok(proxy) = build_proxy() {
+            builder = builder.proxy(proxy);
+        }
+        // connect to the address checked above, not to what a second lookup returns
+        if let Some(domain) = url.domain() {
+            builder = builder.resolve(domain, addr);
+        }
+        let resp = builder.build()?.get(url.clone()).send().await?;
+        let location = resp.headers().get(reqwest::header::LOCATION);
+        match location.and_then(|x| x.to_str().ok()) {
+            Some(location) if resp.status().is_redirection() => url = url.join(location)?,
+            _ => return read_body(resp).await,
+        }
+    }
+    bail!("Too many redirects")
+}
+
 mod test {
     #[test]
     fn test_proxy() {
@@ -96,4 +194,24 @@ mod test {
         );
         assert_eq!(super::get_auth("https://xample.com"), None);
     }
+
+    #[test]
+    fn test_is_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!super::is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111"] {
+            assert!(super::is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
 }
@@ -0,0 +1,98 @@
+use super::{http_client, send, sse, ChatRequest, ChatStream, Provider};
+use crate::{get_env, get_env_map, get_env_or, Result};
+use anyhow::bail;
+use async_openai::types::CreateChatCompletionResponse;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+// https://learn.microsoft.com/en-us/azure/cognitive-services/openai/reference#chat-completions
+pub struct Azure {
+    client: reqwest::Client,
+    resource: String,
+    key: String,
+    api_version: String,
+    deployments: Vec<(String, String)>,
+}
+
+impl Azure {
+    pub fn from_env() -> Self {
+        Self {
+            client: http_client(),
+            resource: get_env("AZURE_OPENAI_RESOURCE"),
+            key: get_env_or("AZURE_OPENAI_API_KEY", get_env("OPENAI_API_KEY")),
+            api_version: get_env_or("AZURE_OPENAI_API_VERSION", "2023-05-15"),
+            deployments: get_env_map("AZURE_OPENAI_DEPLOYMENTS"),
+        }
+    }
+
+    /// Models without an explicit mapping use a deployment of the same name.
+    fn deployment<'a>(&'a self, model: &'a str) -> &'a str {
+        self.deployments
+            .iter()
+            .find(|(m, _)| m == model)
+            .map(|(_, d)| d.as_str())
+            .unwrap_or(model)
+    }
+
+    fn base_url(&self) -> String {
+        if self.resource.contains("://") {
+            self.resource.trim_end_matches('/').to_owned()
+        } else {
+            format!("https://{}.openai.azure.com", self.resource)
+        }
+    }
+
+    fn request<T: Serialize>(&self, model: &str, body: &T) -> Result<reqwest::RequestBuilder> {
+        if self.resource.is_empty() {
+            bail!("AZURE_OPENAI_RESOURCE is not set");
+        }
+        let url = format!(
+            "{}/openai/deployments/{}/chat/completions",
+            self.base_url(),
+            self.deployment(model)
+        );
+        Ok(self
+            .client
+            .post(url)
+            .query(&[("api-version", &self.api_version)])
+            .header("api-key", &self.key)
+            .json(body))
+    }
+}
+
+#[async_trait::async_trait]
+impl Provider for Azure {
+    fn name(&self) -> &'static str {
+        "azure"
+    }
+
+    async fn chat(&self, req: ChatRequest) -> Result<CreateChatCompletionResponse> {
+        Ok(send(self.request(&req.model, &req)?).await?.json().await?)
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> Result<ChatStream> {
+        Ok(sse(send(self.request(&req.model, &req)?).await?))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        Ok(self.deployments.iter().map(|(m, _)| m.clone()).collect())
+    }
+
+    async fn chat_value(&self, body: Value) -> Result<Value> {
+        let model = body["model"].as_str().unwrap_or_default();
+        Ok(send(self.request(model, &body)?).await?.json().await?)
+    }
+
+    fn config(&self) -> Value {
+        let deployments: serde_json::Map<_, _> = self
+            .deployments
+            .iter()
+            .map(|(m, d)| (m.clone(), json!(d)))
+            .collect();
+        json!({
+            "resource": self.resource,
+            "apiVersion": self.api_version,
+            "deployments": deployments,
+        })
+    }
+}
@@ -0,0 +1,92 @@
+use super::{http_client, send, sse};
+use crate::{get_env, get_env_or, Result};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use serde_json::{json, Value};
+
+/// Progress of an answer from the ChatGPT backend-api, `text` is the whole answer so far.
+#[derive(Debug, Clone, Default)]
+pub struct ConversationEvent {
+    pub conversation_id: String,
+    pub message_id: String,
+    pub text: String,
+}
+
+// https://github.com/transitive-bullshit/chatgpt-api/blob/07dcc5df31476fb773a46d103136632e12762179/src/chatgpt-unofficial-proxy-api.ts#L174
+pub struct ChatGPT {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+    model: String,
+}
+
+/// Access token mode is on when both `API_REVERSE_PROXY` and `OPENAI_ACCESS_TOKEN` are set,
+/// a reverse proxy alone stays an OpenAI api base url as it used to be.
+pub fn is_enabled() -> bool {
+    !get_env("API_REVERSE_PROXY").is_empty() && !get_env("OPENAI_ACCESS_TOKEN").is_empty()
+}
+
+impl ChatGPT {
+    pub fn from_env() -> Self {
+        Self {
+            client: http_client(),
+            url: get_env("API_REVERSE_PROXY"),
+            token: get_env("OPENAI_ACCESS_TOKEN"),
+            model: get_env_or("CHATGPT_MODEL", "text-davinci-002-render-sha"),
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Send `prompt` as a new user message `message_id`.
+    ///
+    /// A new conversation still needs a `parent_message_id`, any fresh uuid will do.
+    pub async fn conversation(
+        &self,
+        prompt: &str,
+        message_id: &str,
+        parent_message_id: &str,
+        conversation_id: Option<&str>,
+    ) -> Result<BoxStream<'static, Result<ConversationEvent>>> {
+        let mut body = json!({
+            "action": "next",
+            "messages": [{
+                "id": message_id,
+                "role": "user",
+                "content": {"content_type": "text", "parts": [prompt]},
+            }],
+            "model": self.model,
+            "parent_message_id": parent_message_id,
+        });
+        if let Some(id) = conversation_id {
+            body["conversation_id"] = json!(id);
+        }
+        let req = self
+            .client
+            .post(&self.url)
+            .bearer_auth(&self.token)
+            .header("Accept", "text/event-stream")
+            .json(&body);
+        Ok(sse::<Value>(send(req).await?)
+            .try_filter_map(|x| async move {
+                if let Some(err) = x["error"].as_str() {
+                    anyhow::bail!("{err}");
+                }
+                let message = &x["message"];
+                // newer versions also stream back the user and system messages
+                if message["author"]["role"].as_str() != Some("assistant") {
+                    return Ok(None);
+                }
+                Ok(Some(ConversationEvent {
+                    conversation_id: x["conversation_id"].as_str().unwrap_or_default().to_owned(),
+                    message_id: message["id"].as_str().unwrap_or_default().to_owned(),
+                    text: message["content"]["parts"][0]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_owned(),
+                }))
+            })
+            .boxed())
+    }
+}
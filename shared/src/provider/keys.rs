@@ -0,0 +1,161 @@
+use super::UpstreamError;
+use crate::{get_env, get_env_list, now_ms};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+/// Cool down of a rate limited key when upstream gives no `Retry-After`.
+const DEFAULT_COOLDOWN_MS: u64 = 60 * 1000;
+/// Cool down of a key out of quota, which won't come back by itself soon.
+const QUOTA_COOLDOWN_MS: u64 = 60 * 60 * 1000;
+
+#[derive(Default)]
+struct KeyState {
+    uses: u64,
+    limited: u64,
+    last_limited_at: u64,
+    cooldown_until: u64,
+    last_error: String,
+}
+
+#[derive(Default)]
+struct Pool {
+    cursor: usize,
+    keys: HashMap<String, KeyState>,
+}
+
+static POOL: Lazy<Mutex<Pool>> = Lazy::new(Default::default);
+
+/// `OPENAI_API_KEY` may hold several comma separated keys.
+pub fn get_keys() -> Vec<String> {
+    get_env_list("OPENAI_API_KEY")
+}
+
+/// `sk-...abcd`, safe to show in logs and stats. Keys too short to hide anything all read
+/// `***`.
+pub fn mask(key: &str) -> String {
+    if key.chars().count() < 8 {
+        return "***".to_owned();
+    }
+    let tail: String = key
+        .chars()
+        .rev()
+        .take(4)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    format!("{}...{tail}", key.chars().take(3).collect::<String>())
+}
+
+/// The masked key with part of its SHA-256, usage and budgets are kept by it so that keys
+/// ending alike are not mixed up.
+pub fn account_id(key: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(key));
+    format!("{} #{}", mask(key), &hash[..12])
+}
+
+/// Pick a key for the next request, skipping `exclude` and keys cooling down.
+///
+/// If every key is cooling down the one that recovers first is used anyway.
+pub fn pick(exclude: &[String]) -> Option<String> {
+    let keys: Vec<String> = get_keys()
+        .into_iter()
+        .filter(|x| !exclude.contains(x))
+        .collect();
+    if keys.is_empty() {
+        return None;
+    }
+    let now = now_ms();
+    let mut pool = POOL.lock().unwrap();
+    let ready: Vec<&String> = keys
+        .iter()
+        .filter(|k| pool.keys.get(*k).map(|x| x.cooldown_until).unwrap_or(0) <= now)
+        .collect();
+    let key = if ready.is_empty() {
+        keys.iter()
+            .min_by_key(|k| pool.keys.get(*k).map(|x| x.cooldown_until).unwrap_or(0))
+            .unwrap()
+            .clone()
+    } else if get_env("OPENAI_API_KEY_STRATEGY") == "least-recently-limited" {
+        ready
+            .into_iter()
+            .min_by_key(|k| pool.keys.get(*k).map(|x| x.last_limited_at).unwrap_or(0))
+            .unwrap()
+            .clone()
+    } else {
+        pool.cursor = pool.cursor.wrapping_add(1);
+        ready[pool.cursor % ready.len()].clone()
+    };
+    pool.keys.entry(key.clone()).or_default().uses += 1;
+    Some(key)
+}
+
+/// Whether another key is worth trying after this error.
+pub fn is_rate_limit(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<UpstreamError>(), Some(e) if e.status == 429)
+}
+
+/// Put the key aside after a 429 or quota error.
+pub fn mark_limited(key: &str, err: &anyhow::Error) {
+    let upstream = err.downcast_ref::<UpstreamError>();
+    let cooldown = match upstream {
+        Some(e) if e.code.as_deref() == Some("insufficient_quota") => QUOTA_COOLDOWN_MS,
+        Some(UpstreamError {
+            retry_after: Some(x),
+            ..
+        }) => x.as_millis() as u64,
+        _ => get_env("KEY_COOLDOWN_MS")
+            .parse()
+            .unwrap_or(DEFAULT_COOLDOWN_MS),
+    };
+    log::warn!(
+        "Key {} cooling down for {:?}: {err}",
+        mask(key),
+        Duration::from_millis(cooldown)
+    );
+    let now = now_ms();
+    let mut pool = POOL.lock().unwrap();
+    let state = pool.keys.entry(key.to_owned()).or_default();
+    state.limited += 1;
+    state.last_limited_at = now;
+    state.cooldown_until = now + cooldown;
+    state.last_error = err.to_string();
+}
+
+/// Per key health for `/api/config`, keys are masked.
+pub fn health() -> Vec<Value> {
+    let now = now_ms();
+    let pool = POOL.lock().unwrap();
+    get_keys()
+        .iter()
+        .map(|key| {
+            let state = pool.keys.get(key);
+            let cooldown_until = state.map(|x| x.cooldown_until).unwrap_or(0);
+            json!({
+                "key": mask(key),
+                "available": cooldown_until <= now,
+                "cooldownMs": cooldown_until.saturating_sub(now),
+                "uses": state.map(|x| x.uses).unwrap_or(0),
+                "limited": state.map(|x| x.limited).unwrap_or(0),
+                "lastError": state.map(|x| x.last_error.as_str()).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_mask() {
+        assert_eq!(super::mask("sk-1234567890abcd"), "sk-...abcd");
+        assert_eq!(super::mask("ab"), "***");
+        assert_eq!(super::mask("1234567"), "***");
+        assert_eq!(super::mask("12345678"), "123...5678");
+        let (a, b) = ("sk-aaaa1234abcd", "sk-bbbb1234abcd");
+        assert_eq!(super::mask(a), super::mask(b));
+        assert_ne!(super::account_id(a), super::account_id(b));
+        assert!(super::account_id(a).starts_with("sk-...abcd #"));
+    }
+}
@@ -0,0 +1,261 @@
+use crate::{get_env_or, Result};
+use anyhow::bail;
+use async_openai::types::{
+    ChatCompletionRequestMessage, CreateChatCompletionRequest, CreateChatCompletionResponse,
+    CreateChatCompletionStreamResponse,
+};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::time::Duration;
+use tiktoken_rs::{
+    async_openai::num_tokens_from_messages, cl100k_base, model::get_context_size, CoreBPE,
+};
+
+pub mod azure;
+pub mod chatgpt;
+pub mod keys;
+pub mod ollama;
+pub mod openai;
+
+pub type ChatStream = BoxStream<'static, Result<CreateChatCompletionStreamResponse>>;
+
+/// `CreateChatCompletionRequest` with the fields `async-openai` 0.10 has no types for.
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct ChatRequest {
+    #[serde(flatten)]
+    pub inner: CreateChatCompletionRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+}
+
+impl From<CreateChatCompletionRequest> for ChatRequest {
+    fn from(inner: CreateChatCompletionRequest) -> Self {
+        Self { inner, seed: None }
+    }
+}
+
+impl std::ops::Deref for ChatRequest {
+    type Target = CreateChatCompletionRequest;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+/// A chat completion backend.
+///
+/// Requests and responses use the OpenAI wire types, so `build_messages` and the
+/// streaming loop in `gpt::chat_process` stay the same whatever the vendor is.
+#[async_trait::async_trait]
+pub trait Provider: Send + Sync {
+    /// Name used in logs and reported by `/api/config`.
+    fn name(&self) -> &'static str;
+
+    async fn chat(&self, req: ChatRequest) -> Result<CreateChatCompletionResponse>;
+
+    async fn chat_stream(&self, req: ChatRequest) -> Result<ChatStream>;
+
+    async fn list_models(&self) -> Result<Vec<String>>;
+
+    /// Non-streaming chat with a raw OpenAI request body, for fields `async-openai` has no
+    /// types for, e.g. `functions` and `function` role messages.
+    async fn chat_value(&self, _body: Value) -> Result<Value> {
+        Err(anyhow::anyhow!(
+            "{} does not support function calling",
+            self.name()
+        ))
+    }
+
+    /// tiktoken only knows OpenAI models, anything else is estimated with `cl100k_base`.
+    fn num_tokens(&self, model: &str, messages: &[ChatCompletionRequestMessage]) -> Result<usize> {
+        num_tokens_from_messages(model, messages).or_else(|_| estimate_tokens(messages))
+    }
+
+    fn context_size(&self, model: &str) -> usize {
+        get_context_size(model)
+    }
+
+    /// Whether one request can ask for several answers with `n`.
+    fn supports_n(&self) -> bool {
+        true
+    }
+
+    /// The pooled key this provider was built with, if any.
+    fn api_key(&self) -> &str {
+        ""
+    }
+
+    /// Provider specific settings shown by `/api/config`.
+    fn config(&self) -> Value {
+        Value::Null
+    }
+}
+
+/// Build the provider selected by `LLM_PROVIDER`.
+///
+/// Built on every request, like the old `get_default_client`, because the desktop app
+/// changes the environment at runtime.
+pub fn get_provider() -> Result<Box<dyn Provider>> {
+    get_provider_excluding(&[])
+}
+
+/// Like `get_provider` but avoids pooled keys which already failed this request.
+pub fn get_provider_excluding(keys: &[String]) -> Result<Box<dyn Provider>> {
+    let name = get_env_or("LLM_PROVIDER", "openai").to_lowercase();
+    Ok(match name.as_str() {
+        "openai" => Box::new(openai::OpenAI::new(
+            &openai::get_url(),
+            &keys::pick(keys).unwrap_or_default(),
+        )),
+        "azure" => Box::new(azure::Azure::from_env()),
+        "ollama" => Box::new(ollama::Ollama::from_env()),
+        _ => bail!("Unknown LLM_PROVIDER: {name}"),
+    })
+}
+
+static CL100K: Lazy<Option<CoreBPE>> = Lazy::new(|| cl100k_base().ok());
+
+/// Count like gpt-3.5-turbo does: 4 tokens of framing per message, 3 to prime the reply.
+pub fn estimate_tokens(messages: &[ChatCompletionRequestMessage]) -> Result<usize> {
+    let bpe = CL100K
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to load cl100k_base"))?;
+    Ok(count_messages(bpe, messages))
+}
+
+pub(crate) fn count_messages(bpe: &CoreBPE, messages: &[ChatCompletionRequestMessage]) -> usize {
+    messages
+        .iter()
+        .map(|m| 4 + bpe.encode_with_special_tokens(&m.content).len())
+        .sum::<usize>()
+        + 3
+}
+
+/// Tokens of a bare text with `cl100k_base`, 0 if it failed to load.
+pub fn count_tokens(text: &str) -> usize {
+    CL100K
+        .as_ref()
+        .map(|bpe| bpe.encode_with_special_tokens(text).len())
+        .unwrap_or(0)
+}
+
+pub(crate) fn http_client() -> reqwest::Client {
+    crate::network::build_proxy_client().unwrap_or_default()
+}
+
+/// A non-2xx answer from upstream.
+#[derive(Debug)]
+pub struct UpstreamError {
+    pub status: u16,
+    /// `error.code` of an OpenAI error body, e.g. `insufficient_quota`
+    pub code: Option<String>,
+    pub message: String,
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+/// Whether retrying the same call later may succeed: rate limits (but not an exhausted
+/// quota), gateway errors and dropped connections.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    if let Some(e) = err.downcast_ref::<UpstreamError>() {
+        return match e.status {
+            429 => e.code.as_deref() != Some("insufficient_quota"),
+            500 | 502 | 503 | 504 => true,
+            _ => false,
+        };
+    }
+    if let Some(e) = err.downcast_ref::<reqwest::Error>() {
+        return e.is_connect() || e.is_timeout() || e.is_request() || e.is_body();
+    }
+    false
+}
+
+/// Send the request and turn a non-2xx answer into an `UpstreamError`.
+pub(crate) async fn send(req: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+    let resp = req.send().await?;
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let retry_after = resp
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse().ok())
+        .map(Duration::from_secs);
+    let body = resp.text().await.unwrap_or_default();
+    let body_json = serde_json::from_str::<Value>(&body).unwrap_or_default();
+    let error = &body_json["error"];
+    let message = match error {
+        Value::String(s) => Some(s.clone()),
+        e => e["message"].as_str().map(|s| s.to_owned()),
+    };
+    Err(UpstreamError {
+        status: status.as_u16(),
+        code: error["code"].as_str().map(|s| s.to_owned()),
+        message: message.unwrap_or(body),
+        retry_after,
+    }
+    .into())
+}
+
+async fn next_line(resp: &mut reqwest::Response, buf: &mut Vec<u8>) -> Result<Option<String>> {
+    loop {
+        if let Some(pos) = buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=pos).collect();
+            return Ok(Some(String::from_utf8_lossy(&line).trim_end().to_owned()));
+        }
+        match resp.chunk().await? {
+            Some(chunk) => buf.extend_from_slice(&chunk),
+            None if buf.is_empty() => return Ok(None),
+            None => {
+                let line = String::from_utf8_lossy(buf).trim_end().to_owned();
+                buf.clear();
+                return Ok(Some(line));
+            }
+        }
+    }
+}
+
+/// Split a response body into lines as they arrive.
+pub(crate) fn lines(resp: reqwest::Response) -> BoxStream<'static, Result<String>> {
+    stream::unfold(Some((resp, Vec::new())), |state| async move {
+        let (mut resp, mut buf) = state?;
+        match next_line(&mut resp, &mut buf).await {
+            Ok(Some(line)) => Some((Ok(line), Some((resp, buf)))),
+            Ok(None) => None,
+            Err(err) => Some((Err(err), None)),
+        }
+    })
+    .boxed()
+}
+
+/// Decode the `data:` events of a server-sent event stream.
+pub(crate) fn sse<T: DeserializeOwned + Send + 'static>(
+    resp: reqwest::Response,
+) -> BoxStream<'static, Result<T>> {
+    lines(resp)
+        .try_filter_map(|line| async move {
+            let data = match line.strip_prefix("data:") {
+                Some(data) => data.trim(),
+                None => return Ok(None),
+            };
+            if data.is_empty() || data == "[DONE]" {
+                return Ok(None);
+            }
+            Ok(Some(serde_json::from_str::<T>(data)?))
+        })
+        .boxed()
+}
@@ -0,0 +1,161 @@
+use super::{http_client, lines, send, ChatRequest, ChatStream, Provider};
+use crate::{get_env, get_env_or, Result};
+use async_openai::types::{CreateChatCompletionResponse, CreateChatCompletionStreamResponse};
+use futures::{StreamExt, TryStreamExt};
+use serde_json::{json, Value};
+
+/// Ollama's default `num_ctx`
+const DEFAULT_NUM_CTX: usize = 2048;
+
+// https://github.com/jmorganca/ollama/blob/main/docs/api.md
+pub struct Ollama {
+    client: reqwest::Client,
+    host: String,
+    num_ctx: usize,
+}
+
+impl Ollama {
+    pub fn from_env() -> Self {
+        let mut host = get_env_or("OLLAMA_HOST", "http://localhost:11434");
+        if !host.contains("://") {
+            host = format!("http://{host}");
+        }
+        Self {
+            client: http_client(),
+            host: host.trim_end_matches('/').to_owned(),
+            num_ctx: get_env("OLLAMA_NUM_CTX").parse().unwrap_or(DEFAULT_NUM_CTX),
+        }
+    }
+
+    fn request(&self, req: &ChatRequest, stream: bool) -> reqwest::RequestBuilder {
+        let messages: Vec<Value> = req
+            .messages
+            .iter()
+            .map(|m| json!({"role": m.role, "content": m.content}))
+            .collect();
+        let mut options = json!({ "num_ctx": self.num_ctx });
+        if let Some(x) = req.temperature {
+            options["temperature"] = json!(x);
+        }
+        if let Some(x) = req.top_p {
+            options["top_p"] = json!(x);
+        }
+        if let Some(x) = req.max_tokens {
+            options["num_predict"] = json!(x);
+        }
+        if let Some(x) = req.presence_penalty {
+            options["presence_penalty"] = json!(x);
+        }
+        if let Some(x) = req.frequency_penalty {
+            options["frequency_penalty"] = json!(x);
+        }
+        if let Some(x) = &req.stop {
+            options["stop"] = json!(x);
+        }
+        if let Some(x) = req.seed {
+            options["seed"] = json!(x);
+        }
+        self.client
+            .post(format!("{}/api/chat", self.host))
+            .json(&json!({
+                "model": req.model,
+                "messages": messages,
+                "stream": stream,
+                "options": options,
+            }))
+    }
+}
+
+/// Ollama reports `done_reason` only in recent versions.
+fn finish_reason(x: &Value) -> Value {
+    match x["done"].as_bool() {
+        Some(true) => json!(x["done_reason"].as_str().unwrap_or("stop")),
+        _ => Value::Null,
+    }
+}
+
+fn to_response(x: Value) -> Result<CreateChatCompletionResponse> {
+    let prompt_tokens = x["prompt_eval_count"].as_u64().unwrap_or_default();
+    let completion_tokens = x["eval_count"].as_u64().unwrap_or_default();
+    Ok(serde_json::from_value(json!({
+        "id": "",
+        "object": "chat.completion",
+        "created": 0,
+        "model": x["model"],
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        },
+        "choices": [{
+            "index": 0,
+            "message": {
+                "role": "assistant",
+                "content": x["message"]["content"].as_str().unwrap_or_default(),
+            },
+            "finish_reason": finish_reason(&x),
+        }],
+    }))?)
+}
+
+fn to_chunk(x: Value) -> Result<CreateChatCompletionStreamResponse> {
+    let mut delta = json!({});
+    if let Some(content) = x["message"]["content"].as_str() {
+        delta["content"] = json!(content);
+    }
+    Ok(serde_json::from_value(json!({
+        "id": "",
+        "object": "chat.completion.chunk",
+        "created": 0,
+        "model": x["model"],
+        "choices": [{
+            "index": 0,
+            "delta": delta,
+            "finish_reason": finish_reason(&x),
+        }],
+    }))?)
+}
+
+#[async_trait::async_trait]
+impl Provider for Ollama {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    async fn chat(&self, req: ChatRequest) -> Result<CreateChatCompletionResponse> {
+        to_response(send(self.request(&req, false)).await?.json().await?)
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> Result<ChatStream> {
+        let resp = send(self.request(&req, true)).await?;
+        Ok(lines(resp)
+            .try_filter(|line| futures::future::ready(!line.is_empty()))
+            .and_then(|line| async move { to_chunk(serde_json::from_str(&line)?) })
+            .boxed())
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let url = format!("{}/api/tags", self.host);
+        let resp: Value = send(self.client.get(url)).await?.json().await?;
+        Ok(resp["models"]
+            .as_array()
+            .map(|x| {
+                x.iter()
+                    .filter_map(|m| m["name"].as_str().map(|s| s.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn context_size(&self, _model: &str) -> usize {
+        self.num_ctx
+    }
+
+    fn supports_n(&self) -> bool {
+        false
+    }
+
+    fn config(&self) -> Value {
+        json!({ "host": self.host, "numCtx": self.num_ctx })
+    }
+}
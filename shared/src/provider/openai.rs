@@ -0,0 +1,83 @@
+use super::{http_client, keys, send, sse, ChatRequest, ChatStream, Provider};
+use crate::{get_env, Result};
+use async_openai::{types::CreateChatCompletionResponse, API_BASE};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+// Talks HTTP directly rather than through `async_openai::Client`, which retries 429s on its
+// own for minutes and hides the status code we need to rotate keys.
+pub struct OpenAI {
+    client: reqwest::Client,
+    url: String,
+    key: String,
+}
+
+impl OpenAI {
+    pub fn new(url: &str, key: &str) -> Self {
+        Self {
+            client: http_client(),
+            url: if url.is_empty() { API_BASE } else { url }
+                .trim_end_matches('/')
+                .to_owned(),
+            key: key.to_owned(),
+        }
+    }
+
+    fn chat_request<T: Serialize>(&self, body: &T) -> reqwest::RequestBuilder {
+        self.client
+            .post(format!("{}/chat/completions", self.url))
+            .bearer_auth(&self.key)
+            .json(body)
+    }
+}
+
+#[async_trait::async_trait]
+impl Provider for OpenAI {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn chat(&self, req: ChatRequest) -> Result<CreateChatCompletionResponse> {
+        Ok(send(self.chat_request(&req)).await?.json().await?)
+    }
+
+    async fn chat_stream(&self, req: ChatRequest) -> Result<ChatStream> {
+        Ok(sse(send(self.chat_request(&req)).await?))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let req = self
+            .client
+            .get(format!("{}/models", self.url))
+            .bearer_auth(&self.key);
+        let resp: Value = send(req).await?.json().await?;
+        Ok(resp["data"]
+            .as_array()
+            .map(|x| {
+                x.iter()
+                    .filter_map(|m| m["id"].as_str().map(|s| s.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn chat_value(&self, body: Value) -> Result<Value> {
+        Ok(send(self.chat_request(&body)).await?.json().await?)
+    }
+
+    fn api_key(&self) -> &str {
+        &self.key
+    }
+
+    fn config(&self) -> Value {
+        json!({ "keys": keys::health() })
+    }
+}
+
+/// `API_REVERSE_PROXY` unless it serves access token mode, then `OPENAI_API_BASE_URL`.
+pub(crate) fn get_url() -> String {
+    match get_env("API_REVERSE_PROXY") {
+        url if url.is_empty() || super::chatgpt::is_enabled() => get_env("OPENAI_API_BASE_URL"),
+        url => url,
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{store, Result};
+use anyhow::bail;
+use async_openai::types::Stop;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// Most stop sequences OpenAI accepts.
+const MAX_STOP: usize = 4;
+/// Most completions one request may ask for.
+pub const MAX_N: u8 = 8;
+
+/// Sampling parameters of a request, remembered per conversation so clients send only
+/// what changes, except `n` which is for one request. Unset ones are left to upstream.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Sampling {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "top_p")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    /// Token id to a bias from -100 to 100.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<String, f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// End user id passed on to upstream for abuse monitoring.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Number of answers to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u8>,
+}
+
+fn check_range(name: &str, value: Option<f32>, min: f32, max: f32) -> Result<()> {
+    match value {
+        Some(x) if !(min..=max).contains(&x) => {
+            bail!("{name} must be between {min} and {max}, not {x}")
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Sampling {
+    pub fn validate(&self) -> Result<()> {
+        check_range("temperature", self.temperature, 0.0, 2.0)?;
+        check_range("top_p", self.top_p, 0.0, 1.0)?;
+        check_range("presencePenalty", self.presence_penalty, -2.0, 2.0)?;
+        check_range("frequencyPenalty", self.frequency_penalty, -2.0, 2.0)?;
+        if let Some(stop) = &self.stop {
+            if stop.len() > MAX_STOP || stop.iter().any(|x| x.is_empty()) {
+                bail!("stop takes up to {MAX_STOP} non-empty sequences");
+            }
+        }
+        for (token, bias) in self.logit_bias.iter().flatten() {
+            if token.parse::<u32>().is_err() {
+                bail!("logitBias keys must be token ids, not {token}");
+            }
+            check_range("logitBias", Some(*bias), -100.0, 100.0)?;
+        }
+        if let Some(user) = &self.user {
+            if user.is_empty() || user.len() > 256 {
+                bail!("user must be 1 to 256 characters");
+            }
+        }
+        if let Some(n) = self.n {
+            if !(1..=MAX_N).contains(&n) {
+                bail!("n must be between 1 and {MAX_N}, not {n}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Parameters of `self`, those it leaves unset taken from `other`.
+    pub fn or(self, other: Sampling) -> Sampling {
+        Sampling {
+            temperature: self.temperature.or(other.temperature),
+            top_p: self.top_p.or(other.top_p),
+            presence_penalty: self.presence_penalty.or(other.presence_penalty),
+            frequency_penalty: self.frequency_penalty.or(other.frequency_penalty),
+            stop: self.stop.or(other.stop),
+            logit_bias: self.logit_bias.or(other.logit_bias),
+            seed: self.seed.or(other.seed),
+            user: self.user.or(other.user),
+            n: self.n.or(other.n),
+        }
+    }
+
+    pub(crate) fn stop(&self) -> Option<Stop> {
+        self.stop.clone().map(Stop::StringArray)
+    }
+
+    pub(crate) fn logit_bias(&self) -> Option<HashMap<String, Value>> {
+        self.logit_bias
+            .as_ref()
+            .map(|x| x.iter().map(|(k, v)| (k.clone(), json!(v))).collect())
+    }
+}
+
+// `sampling/{conversation}` -> Sampling
+#[inline]
+pub(crate) fn sampling_key(conversation: &str) -> String {
+    format!("sampling/{conversation}")
+}
+
+/// Parameters remembered for a conversation.
+pub(crate) fn get(conversation: &str) -> Sampling {
+    match store::get(sampling_key(conversation)) {
+        Ok(Some(data)) => serde_json::from_slice(&data).unwrap_or_default(),
+        _ => Sampling::default(),
+    }
+}
+
+/// Complete `sampling` with what the conversation used so far, validate and remember it.
+/// With `reset` what was used so far is forgotten instead.
+pub(crate) fn remember(conversation: &str, sampling: Sampling, reset: bool) -> Result<Sampling> {
+    let stored = get(conversation);
+    let previous = match reset {
+        true => Sampling::default(),
+        false => Sampling {
+            n: None,
+            ..stored.clone()
+        },
+    };
+    let sampling = sampling.or(previous);
+    sampling.validate()?;
+    // more answers to every later question would also rule out tools
+    let kept = Sampling {
+        n: None,
+        ..sampling.clone()
+    };
+    if kept == Sampling::default() {
+        store::delete(sampling_key(conversation))?;
+    } else if kept != stored {
+        store::put(sampling_key(conversation), serde_json::to_vec(&kept)?)?;
+    }
+    Ok(sampling)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let sampling = Sampling {
+            temperature: Some(1.0),
+            stop: Some(vec!["\n".to_owned()]),
+            logit_bias: Some(HashMap::from([("50256".to_owned(), -100.0)])),
+            n: Some(3),
+            ..Default::default()
+        };
+        assert!(sampling.validate().is_ok());
+        let sampling = Sampling {
+            top_p: Some(1.5),
+            ..Default::default()
+        };
+        assert!(sampling.validate().is_err());
+        let sampling = Sampling {
+            logit_bias: Some(HashMap::from([("hello".to_owned(), 1.0)])),
+            ..Default::default()
+        };
+        assert!(sampling.validate().is_err());
+    }
+
+    #[test]
+    fn test_remember() {
+        let conversation = uuid::Uuid::new_v4().to_string();
+        let sampling = Sampling {
+            temperature: Some(0.5),
+            n: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(remember(&conversation, sampling, false).unwrap().n, Some(3));
+        // `n` is for one request, the rest sticks
+        let sampling = remember(&conversation, Sampling::default(), false).unwrap();
+        assert_eq!(sampling.temperature, Some(0.5));
+        assert_eq!(sampling.n, None);
+        let sampling = Sampling {
+            top_p: Some(0.9),
+            ..Default::default()
+        };
+        let sampling = remember(&conversation, sampling, true).unwrap();
+        assert_eq!(sampling.temperature, None);
+        assert_eq!(get(&conversation).top_p, Some(0.9));
+    }
+}
@@ -9,6 +9,10 @@ enum Store {
 
 // NB: db is automatically closed at end of lifetime
 static STORE: Lazy<Mutex<Store>> = Lazy::new(|| {
+    // tests never touch the user's store
+    if cfg!(test) {
+        return Mutex::new(Store::Map(HashMap::new()));
+    }
     Mutex::new(match create_db() {
         Err(err) => {
             crate::log::error!("Failed to create store: {err}");
@@ -18,16 +22,21 @@ static STORE: Lazy<Mutex<Store>> = Lazy::new(|| {
     })
 });
 
-fn create_db() -> crate::Result<Db> {
+/// `STORE_PATH`, or `~/.chatgpt`.
+pub(crate) fn data_dir() -> std::path::PathBuf {
     use std::path::Path;
     let store_path = crate::get_env("STORE_PATH");
-    let mut path = if store_path.is_empty() {
+    if store_path.is_empty() {
         let mut tmp = dirs::home_dir().unwrap_or(Path::new(".").into());
         tmp.push(".chatgpt");
         tmp
     } else {
         Path::new(&store_path).to_path_buf()
-    };
+    }
+}
+
+fn create_db() -> crate::Result<Db> {
+    let mut path = data_dir();
     if !path.exists() {
         std::fs::create_dir_all(&path)?;
     }
@@ -82,3 +91,94 @@ where
     }
     Ok(())
 }
+
+/// Write many pairs at once, all or nothing with sled.
+pub fn put_all(items: Vec<(Vec<u8>, Vec<u8>)>) -> crate::Result<()> {
+    let mut guard = STORE.lock().unwrap();
+    match guard.deref_mut() {
+        Store::DB(db) => {
+            let mut batch = sled::Batch::default();
+            items.into_iter().for_each(|(k, v)| batch.insert(k, v));
+            db.apply_batch(batch)?;
+        }
+        Store::Map(m) => m.extend(items),
+    }
+    Ok(())
+}
+
+/// Read, change and write `k` back without other store calls in between.
+pub fn update<K, F>(k: K, f: F) -> crate::Result<()>
+where
+    K: AsRef<[u8]>,
+    F: FnOnce(Option<Vec<u8>>) -> crate::Result<Vec<u8>>,
+{
+    let mut guard = STORE.lock().unwrap();
+    match guard.deref_mut() {
+        Store::DB(db) => {
+            let v = f(db.get(&k)?.map(|x| x.to_vec()))?;
+            db.insert(k, v)?;
+        }
+        Store::Map(m) => {
+            let v = f(m.get(k.as_ref()).cloned())?;
+            m.insert(k.as_ref().into(), v);
+        }
+    }
+    Ok(())
+}
+
+/// Key/value pairs whose key starts with `prefix`, sorted by key.
+pub fn scan_prefix<P>(prefix: P) -> crate::Result<Vec<(Vec<u8>, Vec<u8>)>>
+where
+    P: AsRef<[u8]>,
+{
+    let mut guard = STORE.lock().unwrap();
+    match guard.deref_mut() {
+        Store::DB(db) => db
+            .scan_prefix(prefix)
+            .map(|x| Ok(x.map(|(k, v)| (k.to_vec(), v.to_vec()))?))
+            .collect(),
+        Store::Map(m) => {
+            let mut items: Vec<_> = m
+                .iter()
+                .filter(|(k, _)| k.starts_with(prefix.as_ref()))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            items.sort();
+            Ok(items)
+        }
+    }
+}
+
+// Child index: `children/{parent}/{child}` -> when the child was added
+#[inline]
+pub(crate) fn child_key(parent: &str, child: &str) -> String {
+    format!("children/{parent}/{child}")
+}
+
+/// Remember `child` as a reply to `parent`.
+pub fn add_child(parent: &str, child: &str) -> crate::Result<()> {
+    let key = child_key(parent, child);
+    // keep the original position when a message is stored again
+    if get(&key)?.is_none() {
+        put(&key, crate::now_ms().to_be_bytes())?;
+    }
+    Ok(())
+}
+
+pub fn remove_child(parent: &str, child: &str) -> crate::Result<()> {
+    delete(child_key(parent, child))
+}
+
+/// Ids of the replies to `parent`, oldest first.
+pub fn children(parent: &str) -> crate::Result<Vec<String>> {
+    let prefix = child_key(parent, "");
+    let mut children: Vec<(u64, String)> = scan_prefix(&prefix)?
+        .into_iter()
+        .map(|(k, v)| {
+            let id = String::from_utf8_lossy(&k[prefix.len()..]).into_owned();
+            (v.try_into().map(u64::from_be_bytes).unwrap_or(0), id)
+        })
+        .collect();
+    children.sort();
+    Ok(children.into_iter().map(|(_, id)| id).collect())
+}
@@ -0,0 +1,198 @@
+use crate::{
+    get_env, get_env_or,
+    gpt::{self, ChatMessage, Metadata},
+    now_ms,
+    provider::{count_tokens, get_provider},
+    store, usage, Result,
+};
+use async_openai::types::{ChatCompletionRequestMessageArgs, CreateChatCompletionRequest, Role};
+
+/// What happens to history older than the context window.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextStrategy {
+    /// Drop it.
+    #[default]
+    Truncate,
+    /// Condense it into a rolling summary injected after the system message.
+    Summarize,
+}
+
+impl ContextStrategy {
+    /// `CONTEXT_STRATEGY`, `truncate` by default.
+    pub fn from_env() -> Self {
+        match get_env_or("CONTEXT_STRATEGY", "truncate").as_str() {
+            "summarize" => Self::Summarize,
+            _ => Self::Truncate,
+        }
+    }
+}
+
+/// Tokens reserved in the context for the summary, `SUMMARY_MAX_TOKENS` or 512.
+pub(crate) fn max_tokens() -> usize {
+    get_env("SUMMARY_MAX_TOKENS").parse().unwrap_or(512)
+}
+
+/// Summary of a conversation up to and including message `upto`, `summary/{conversation}`
+/// in the store.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct Cached {
+    upto: String,
+    text: String,
+    updated_at: u64,
+}
+
+pub(crate) fn summary_key(conversation: &str) -> String {
+    format!("summary/{conversation}")
+}
+
+fn get_cached(conversation: &str) -> Option<Cached> {
+    match store::get(summary_key(conversation)) {
+        Ok(Some(data)) => serde_json::from_slice(&data).ok(),
+        _ => None,
+    }
+}
+
+/// Drop the summary of `conversation` if it covers message `id`, which is going away. A
+/// summary covers the ancestors of its last message too, and those never go alone.
+pub(crate) fn forget(conversation: &str, id: &str) -> Result<()> {
+    match get_cached(conversation) {
+        Some(cached) if cached.upto == id => store::delete(summary_key(conversation)),
+        _ => Ok(()),
+    }
+}
+
+const INSTRUCTION: &str = "Condense the conversation below into a summary for yourself to \
+continue it later. Keep facts, names, numbers, decisions and open questions, drop small talk. \
+Reply with the summary only.";
+
+/// Prefix of the system message carrying the summary.
+const PREFIX: &str = "Summary of the earlier conversation:\n";
+
+pub(crate) fn as_system_message(summary: &str) -> String {
+    format!("{PREFIX}{summary}")
+}
+
+fn transcript(messages: &[ChatMessage]) -> String {
+    messages
+        .iter()
+        .map(|x| match x.role {
+            Some(Role::Assistant) => format!("Assistant: {}", x.text),
+            _ => format!("User: {}", x.text),
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Summary of `dropped`, the oldest messages of a conversation in order. The cached summary
+/// is reused when it covers them and otherwise rolled forward over the messages it is
+/// missing, a chunk of `context_size` at a time. A summary whose last message is not on
+/// the path, e.g. after an edit forked the conversation, is dropped.
+pub(crate) async fn summarize(
+    conversation: &str,
+    dropped: &[ChatMessage],
+    model: &str,
+    context_size: usize,
+    user: &str,
+) -> Result<String> {
+    let newest = match dropped.last() {
+        Some(x) => &x.id,
+        None => return Ok(String::new()),
+    };
+    let (mut text, mut rest) = match get_cached(conversation) {
+        Some(cached) if &cached.upto == newest => return Ok(cached.text),
+        Some(cached) => match dropped.iter().position(|x| x.id == cached.upto) {
+            Some(i) => (cached.text, &dropped[i + 1..]),
+            None => {
+                store::delete(summary_key(conversation))?;
+                (String::new(), dropped)
+            }
+        },
+        None => (String::new(), dropped),
+    };
+    let budget = context_size
+        .saturating_sub(2 * max_tokens())
+        .max(max_tokens());
+    while !rest.is_empty() {
+        let mut size = count_tokens(&text);
+        let mut n = 0;
+        while n < rest.len() {
+            size += count_tokens(&rest[n].text);
+            if n > 0 && size > budget {
+                break;
+            }
+            n += 1;
+        }
+        let (chunk, next) = rest.split_at(n);
+        text = condense(&text, chunk, model, user).await?;
+        let cached = Cached {
+            upto: chunk.last().unwrap().id.clone(),
+            text: text.clone(),
+            updated_at: now_ms(),
+        };
+        store::put(summary_key(conversation), serde_json::to_vec(&cached)?)?;
+        rest = next;
+    }
+    Ok(text)
+}
+
+/// Fold `messages` into `previous`.
+async fn condense(
+    previous: &str,
+    messages: &[ChatMessage],
+    model: &str,
+    user: &str,
+) -> Result<String> {
+    let mut content = String::new();
+    if !previous.is_empty() {
+        content = format!("Summary so far:\n{previous}\n\nConversation since:\n");
+    }
+    content.push_str(&transcript(messages));
+    let prompt_tokens = count_tokens(INSTRUCTION) + count_tokens(&content);
+    let request = CreateChatCompletionRequest {
+        model: model.to_owned(),
+        max_tokens: Some(max_tokens() as _),
+        messages: vec![
+            ChatCompletionRequestMessageArgs::default()
+                .content(INSTRUCTION)
+                .role(Role::System)
+                .build()?,
+            ChatCompletionRequestMessageArgs::default()
+                .content(content)
+                .role(Role::User)
+                .build()?,
+        ],
+        ..Default::default()
+    };
+    let mut used_key = String::new();
+    let resp = gpt::upstream(&mut used_key, |provider| {
+        let request = request.clone();
+        async move { provider.chat(request.into()).await }
+    })
+    .await?;
+    let text = resp
+        .choices
+        .first()
+        .map(|x| x.message.content.trim().to_owned())
+        .unwrap_or_default();
+    // summaries cost like answers
+    let mut metadata = Metadata {
+        estimated: true,
+        ..Default::default()
+    };
+    metadata.add_usage(&serde_json::to_value(&resp.usage)?);
+    if metadata.estimated {
+        metadata.prompt_tokens = prompt_tokens;
+        metadata.completion_tokens = count_tokens(&text);
+    }
+    let msg = ChatMessage {
+        created_at: Some(now_ms()),
+        model: Some(model.to_owned()),
+        metadata: Some(metadata),
+        ..Default::default()
+    };
+    let account = gpt::usage_account(get_provider()?.name(), &used_key);
+    usage::record(&msg, &account, user).ok();
+    Ok(text)
+}
@@ -0,0 +1,126 @@
+use crate::{format_datetime, get_env_list, now_ms, Result};
+use anyhow::bail;
+use futures::{future::BoxFuture, Future, FutureExt};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::sync::{Arc, RwLock};
+
+/// Tool output fed back to the model is cut to this many chars.
+const MAX_OUTPUT_CHARS: usize = 8000;
+
+type Handler = Arc<dyn Fn(Value) -> BoxFuture<'static, Result<String>> + Send + Sync>;
+
+/// A function the model may call, see https://platform.openai.com/docs/guides/gpt/function-calling
+#[derive(Clone)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments
+    pub parameters: Value,
+    handler: Handler,
+}
+
+impl Tool {
+    pub fn new<F, Fut>(name: &str, description: &str, parameters: Value, handler: F) -> Self
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String>> + Send + 'static,
+    {
+        Self {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            parameters,
+            handler: Arc::new(move |args| handler(args).boxed()),
+        }
+    }
+
+    /// Entry of the `functions` request field.
+    pub fn schema(&self) -> Value {
+        json!({
+            "name": self.name,
+            "description": self.description,
+            "parameters": self.parameters,
+        })
+    }
+
+    /// Run with the raw `arguments` string of a function call, errors are reported to the
+    /// model rather than failing the chat.
+    pub async fn call(&self, arguments: &str) -> String {
+        let args = if arguments.trim().is_empty() {
+            Ok(json!({}))
+        } else {
+            serde_json::from_str(arguments)
+        };
+        let output = match args {
+            Ok(args) => (self.handler)(args).await,
+            Err(err) => Err(err.into()),
+        };
+        let mut output = output.unwrap_or_else(|err| format!("Error: {err}"));
+        if let Some((pos, _)) = output.char_indices().nth(MAX_OUTPUT_CHARS) {
+            output.truncate(pos);
+            output.push_str("\n[truncated]");
+        }
+        output
+    }
+}
+
+static TOOLS: Lazy<RwLock<Vec<Tool>>> = Lazy::new(|| RwLock::new(builtin()));
+
+fn builtin() -> Vec<Tool> {
+    vec![
+        Tool::new(
+            "get_current_time",
+            "Get the current date and time in UTC",
+            json!({"type": "object", "properties": {}}),
+            |_| async { Ok(format!("{} UTC", format_datetime(now_ms()))) },
+        ),
+        Tool::new(
+            "fetch_url",
+            "Fetch the content of a web page",
+            json!({
+                "type": "object",
+                "properties": {"url": {"type": "string", "description": "Absolute http(s) url"}},
+                "required": ["url"],
+            }),
+            |args| async move {
+                match args["url"].as_str() {
+                    Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+                        crate::network::fetch_public(url).await
+                    }
+                    _ => bail!("Invalid url"),
+                }
+            },
+        ),
+    ]
+}
+
+/// Add a tool, replacing the one with the same name.
+pub fn register(tool: Tool) {
+    let mut tools = TOOLS.write().unwrap();
+    tools.retain(|x| x.name != tool.name);
+    tools.push(tool);
+}
+
+/// Registered tools the admin turned on with `ENABLED_TOOLS`.
+pub fn enabled() -> Vec<Tool> {
+    let names = get_env_list("ENABLED_TOOLS");
+    TOOLS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|x| names.contains(&x.name))
+        .cloned()
+        .collect()
+}
+
+/// Look up the tools a request asks for.
+pub fn resolve(names: &[String]) -> Result<Vec<Tool>> {
+    let enabled = enabled();
+    names
+        .iter()
+        .map(|name| match enabled.iter().find(|x| &x.name == name) {
+            Some(tool) => Ok(tool.clone()),
+            None => bail!("Tool {name} is not enabled"),
+        })
+        .collect()
+}
@@ -0,0 +1,252 @@
+use crate::{
+    catalog, format_date, get_env_map,
+    gpt::{ChatMessage, DateRange},
+    now_ms, store, Result,
+};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// USD per 1K prompt and completion tokens of a model, the longest matching prefix of
+/// `MODEL_PRICES` (`model=prompt/completion`) or the model catalog, free if unknown.
+pub fn price(model: &str) -> (f64, f64) {
+    let custom: Vec<(String, f64, f64)> = get_env_map("MODEL_PRICES")
+        .into_iter()
+        .filter_map(|(k, v)| {
+            let (prompt, completion) = v.split_once('/')?;
+            Some((
+                k,
+                prompt.trim().parse().ok()?,
+                completion.trim().parse().ok()?,
+            ))
+        })
+        .collect();
+    let custom = custom
+        .iter()
+        .filter(|(k, _, _)| model.starts_with(k.as_str()))
+        .max_by_key(|(k, _, _)| k.len());
+    if let Some((_, p, c)) = custom {
+        return (*p, *c);
+    }
+    let info = catalog::get(model);
+    (
+        info.prompt_price.unwrap_or_default(),
+        info.completion_price.unwrap_or_default(),
+    )
+}
+
+/// Requests of one day, model, account and user, `usage/{date}/{model}/{account}/{user}` in
+/// the store.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    date: String,
+    model: String,
+    account: String,
+    /// `user_id` of the access token, empty without `AUTH_SECRET_KEY`.
+    #[serde(default)]
+    user: String,
+    requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    /// USD at the prices of the time, missing in records from before it was kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cost: Option<f64>,
+}
+
+/// USD of `prompt_tokens` and `completion_tokens` of `model` at today's prices.
+fn cost(model: &str, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+    let (prompt, completion) = price(model);
+    (prompt_tokens as f64 * prompt + completion_tokens as f64 * completion) / 1000.0
+}
+
+impl Record {
+    /// Cost as recorded, older records priced now.
+    fn cost(&self) -> f64 {
+        self.cost
+            .unwrap_or_else(|| cost(&self.model, self.prompt_tokens, self.completion_tokens))
+    }
+}
+
+/// Users are accounted by the SHA-256 of their access token, which is never stored.
+pub(crate) fn user_id(access_token: &str) -> String {
+    if access_token.is_empty() {
+        String::new()
+    } else {
+        format!("{:x}", Sha256::digest(access_token))
+    }
+}
+
+/// Count an answer against `account`, a `keys::account_id` or a provider name, and `user`.
+pub(crate) fn record(msg: &ChatMessage, account: &str, user: &str) -> Result<()> {
+    let metadata = match msg.metadata() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let date = format_date(msg.created_at.unwrap_or_else(now_ms));
+    let model = msg.model.clone().unwrap_or_default();
+    let (prompt_tokens, completion_tokens) = (
+        metadata.prompt_tokens as u64,
+        metadata.completion_tokens as u64,
+    );
+    let added = cost(&model, prompt_tokens, completion_tokens);
+    // before this request is in the records, or it would be counted twice
+    migrate_totals()?;
+    store::update(format!("usage/{date}/{model}/{account}/{user}"), |v| {
+        let mut record: Record = match v {
+            Some(v) => serde_json::from_slice(&v)?,
+            None => Record {
+                date: date.clone(),
+                model,
+                account: account.to_owned(),
+                user: user.to_owned(),
+                ..Default::default()
+            },
+        };
+        record.cost = Some(record.cost() + added);
+        record.requests += 1;
+        record.prompt_tokens += prompt_tokens;
+        record.completion_tokens += completion_tokens;
+        Ok(serde_json::to_vec(&record)?)
+    })?;
+    let request = Record {
+        date,
+        account: account.to_owned(),
+        user: user.to_owned(),
+        requests: 1,
+        prompt_tokens,
+        completion_tokens,
+        cost: Some(added),
+        ..Default::default()
+    };
+    add_totals(&request)
+}
+
+/// Day `YYYY-MM-DD` and month `YYYY-MM` of `date`, the periods of running totals.
+fn periods(date: &str) -> [&str; 2] {
+    [date, &date[..date.len().min(7)]]
+}
+
+// `totals/{period}/account/{account}` and `totals/{period}/user/{user}` -> Totals
+#[inline]
+fn totals_key(period: &str, scope: &str, who: &str) -> String {
+    format!("totals/{period}/{scope}/{who}")
+}
+
+/// Add `record` to the running totals of its account and user, by day and by month.
+fn add_totals(record: &Record) -> Result<()> {
+    for period in periods(&record.date) {
+        for (scope, who) in [("account", &record.account), ("user", &record.user)] {
+            if who.is_empty() {
+                continue;
+            }
+            store::update(totals_key(period, scope, who), |v| {
+                let mut totals: Totals = match v {
+                    Some(v) => serde_json::from_slice(&v)?,
+                    None => Totals::default(),
+                };
+                totals.add(record);
+                Ok(serde_json::to_vec(&totals)?)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Usage of `account` or `user` (`scope`) in a day or month, kept as it happens so budgets
+/// are checked without going through every record.
+pub(crate) fn totals(period: &str, scope: &str, who: &str) -> Result<Totals> {
+    migrate_totals()?;
+    Ok(match store::get(totals_key(period, scope, who))? {
+        Some(v) => serde_json::from_slice(&v)?,
+        None => Totals::default(),
+    })
+}
+
+const TOTALS_VERSION: &str = "totals/version";
+
+static TOTALS_READY: Lazy<Mutex<bool>> = Lazy::new(Default::default);
+
+/// Running totals started after usage records, those recorded before are added up once.
+fn migrate_totals() -> Result<()> {
+    let mut ready = TOTALS_READY.lock().unwrap();
+    if !*ready && store::get(TOTALS_VERSION)?.is_none() {
+        for (_, v) in store::scan_prefix("usage/")? {
+            if let Ok(record) = serde_json::from_slice::<Record>(&v) {
+                add_totals(&record)?;
+            }
+        }
+        store::put(TOTALS_VERSION, b"1")?;
+    }
+    *ready = true;
+    Ok(())
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Totals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// USD at the prices of the time the requests were made.
+    pub cost: f64,
+}
+
+impl Totals {
+    fn add(&mut self, record: &Record) {
+        self.requests += record.requests;
+        self.prompt_tokens += record.prompt_tokens;
+        self.completion_tokens += record.completion_tokens;
+        self.cost += record.cost();
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    #[serde(flatten)]
+    pub total: Totals,
+    pub by_day: BTreeMap<String, Totals>,
+    pub by_model: BTreeMap<String, Totals>,
+    pub by_account: BTreeMap<String, Totals>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub by_user: BTreeMap<String, Totals>,
+}
+
+/// Usage within `rng`, in total and by day, model, account and user.
+pub fn summary(rng: &DateRange) -> Result<Summary> {
+    let mut summary = Summary::default();
+    for (_, v) in store::scan_prefix("usage/")? {
+        let record: Record = match serde_json::from_slice(&v) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if (!rng.start_date.is_empty() && record.date < rng.start_date)
+            || (!rng.end_date.is_empty() && record.date > rng.end_date)
+        {
+            continue;
+        }
+        summary.total.add(&record);
+        for (map, key) in [
+            (&mut summary.by_day, &record.date),
+            (&mut summary.by_model, &record.model),
+            (&mut summary.by_account, &record.account),
+            (&mut summary.by_user, &record.user),
+        ] {
+            if !key.is_empty() {
+                map.entry(key.clone()).or_default().add(&record);
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_price() {
+        assert_eq!(super::price("gpt-4-0613"), (0.03, 0.06));
+        assert_eq!(super::price("gpt-4-32k-0613"), (0.06, 0.12));
+        assert_eq!(super::price("llama2"), (0.0, 0.0));
+    }
+}
@@ -1,7 +1,10 @@
 use tauri::{command, Window};
 
 use shared::{
+    export::*,
     gpt::*,
+    history::*,
+    import::*,
     serde_json::{self, json, Value},
 };
 
@@ -32,19 +35,77 @@ async fn _call(
         Some(move |data: ChatMessage| {
             let data = serde_json::to_string(&data).unwrap_or_default();
             let val = format!("window['on_progress']['{id}']({{event:{data}}})");
-            window.eval(&val).ok();
+            // fails once the window is gone, which stops the answer
+            std::future::ready(window.eval(&val).map_err(shared::anyhow::Error::from))
         })
     } else {
         None
     };
     Ok(match url.as_ref() {
         "/api/session" => json!(get_session()),
+        "/api/models" => json!(shared::catalog::models().await?),
         "/api/chat-process" => json!(chat_process(serde_json::from_value(params)?, func).await?),
+        "/api/chat-regenerate" => {
+            json!(chat_regenerate(serde_json::from_value(params)?, func).await?)
+        }
+        "/api/chat-abort" => json!(chat_abort(serde_json::from_value(params)?)),
+        "/api/message-children" => json!(message_children(serde_json::from_value(params)?)?),
+        "/api/message-siblings" => json!(message_siblings(serde_json::from_value(params)?)?),
+        "/api/message-path" => json!(message_path(serde_json::from_value(params)?)?),
+        "/api/message-delete" => json!(message_delete(serde_json::from_value(params)?)?),
+        "/api/conversations" => json!(conversations()?),
+        "/api/conversation" => json!(conversation(serde_json::from_value(params)?)?),
+        "/api/conversation-rename" => {
+            json!(conversation_rename(serde_json::from_value(params)?)?)
+        }
+        "/api/conversation-select" => {
+            json!(conversation_select(serde_json::from_value(params)?)?)
+        }
+        "/api/conversation-delete" => {
+            json!(conversation_delete(serde_json::from_value(params)?)?)
+        }
         "/api/config" => json!(chat_config(serde_json::from_value(params)?, false).await?),
         "/api/verify" | _ => Value::Null,
     })
 }
 
+#[command]
+pub fn abort(id: String) -> bool {
+    shared::abort::abort(&id)
+}
+
+#[command]
+pub fn delete_message(id: String) -> std::result::Result<Value, String> {
+    Ok(json!(
+        message_delete(IdBody { id }).map_err(|x| x.to_string())?
+    ))
+}
+
+#[command]
+pub fn delete_conversation(id: String) -> std::result::Result<Value, String> {
+    Ok(json!(
+        conversation_delete(IdBody { id }).map_err(|x| x.to_string())?
+    ))
+}
+
+/// Write a conversation to `path`, picked by the user in a save dialog.
+#[command]
+pub fn export_conversation(body: ExportBody, path: String) -> std::result::Result<(), String> {
+    let export = export(body).map_err(|x| x.to_string())?;
+    std::fs::write(path, export.content).map_err(|x| x.to_string())
+}
+
+/// Import the `conversations.json` of an official ChatGPT data export at `path`.
+#[command]
+pub fn import_conversations(path: String, dry_run: bool) -> std::result::Result<Value, String> {
+    let data = std::fs::read(path).map_err(|x| x.to_string())?;
+    let body = ImportBody {
+        conversations: serde_json::from_slice(&data).map_err(|x| x.to_string())?,
+        dry_run,
+    };
+    Ok(json!(import(body).map_err(|x| x.to_string())?))
+}
+
 #[command]
 pub fn set(key: String, value: String) {
     shared::log::debug!("Set {}={}", key, value);
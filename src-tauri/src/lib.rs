@@ -4,7 +4,17 @@ use app::cmd;
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![cmd::call, cmd::set, cmd::get, cmd::fetch])
+        .invoke_handler(tauri::generate_handler![
+            cmd::call,
+            cmd::abort,
+            cmd::delete_message,
+            cmd::delete_conversation,
+            cmd::export_conversation,
+            cmd::import_conversations,
+            cmd::set,
+            cmd::get,
+            cmd::fetch
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
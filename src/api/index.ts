@@ -13,6 +13,13 @@ export function fetchChatAPIProcess<T = any>(
   params: {
     prompt: string
     lastContext?: { conversationId?: string; parentMessageId?: string }
+    editMessageId?: string
+    contextStrategy?: 'truncate' | 'summarize'
+    maxResponseTokens?: number
+    /** presencePenalty, frequencyPenalty, stop, logitBias, seed, user kept per conversation; n for this request only */
+    sampling?: Record<string, any>
+    /** forget the sampling parameters the conversation used so far */
+    resetSampling?: boolean
     signal?: GenericAbortSignal
     onDownloadProgress?: (progressEvent: AxiosProgressEvent) => void },
 ) {
@@ -22,6 +29,11 @@ export function fetchChatAPIProcess<T = any>(
   let data: Record<string, any> = {
     prompt: params.prompt,
     lastContext: params.lastContext,
+    editMessageId: params.editMessageId,
+    contextStrategy: params.contextStrategy,
+    maxResponseTokens: params.maxResponseTokens,
+    resetSampling: params.resetSampling,
+    ...params.sampling,
   }
 
   if (authStore.isChatGPTAPI) {
@@ -41,12 +53,142 @@ export function fetchChatAPIProcess<T = any>(
   })
 }
 
+export function fetchChatRegenerate<T = any>(
+  params: {
+    id: string
+    contextStrategy?: 'truncate' | 'summarize'
+    maxResponseTokens?: number
+    /** as for fetchChatAPIProcess */
+    sampling?: Record<string, any>
+    resetSampling?: boolean
+    signal?: GenericAbortSignal
+    onDownloadProgress?: (progressEvent: AxiosProgressEvent) => void },
+) {
+  const settingStore = useSettingStore()
+  const authStore = useAuthStore()
+
+  let data: Record<string, any> = {
+    id: params.id,
+    contextStrategy: params.contextStrategy,
+    maxResponseTokens: params.maxResponseTokens,
+    resetSampling: params.resetSampling,
+    ...params.sampling,
+  }
+
+  if (authStore.isChatGPTAPI) {
+    data = {
+      ...data,
+      systemMessage: settingStore.systemMessage,
+      temperature: settingStore.temperature,
+      top_p: settingStore.top_p,
+    }
+  }
+
+  return post<T>({
+    url: '/api/chat-regenerate',
+    data,
+    signal: params.signal,
+    onDownloadProgress: params.onDownloadProgress,
+  })
+}
+
+export function fetchChatAbort<T = any>(id: string) {
+  return post<T>({
+    url: '/api/chat-abort',
+    data: { id },
+  })
+}
+
+export function fetchMessageChildren<T = any>(id: string) {
+  return post<T>({
+    url: '/api/message-children',
+    data: { id },
+  })
+}
+
+export function fetchMessageSiblings<T = any>(id: string) {
+  return post<T>({
+    url: '/api/message-siblings',
+    data: { id },
+  })
+}
+
+export function fetchMessagePath<T = any>(id: string) {
+  return post<T>({
+    url: '/api/message-path',
+    data: { id },
+  })
+}
+
+export function fetchMessageDelete<T = any>(id: string) {
+  return post<T>({
+    url: '/api/message-delete',
+    data: { id },
+  })
+}
+
+export function fetchConversations<T = any>() {
+  return post<T>({
+    url: '/api/conversations',
+  })
+}
+
+export function fetchConversation<T = any>(id: string) {
+  return post<T>({
+    url: '/api/conversation',
+    data: { id },
+  })
+}
+
+export function fetchConversationRename<T = any>(id: string, title: string) {
+  return post<T>({
+    url: '/api/conversation-rename',
+    data: { id, title },
+  })
+}
+
+/** Make message `id`, e.g. one of several answers, the active branch of its conversation */
+export function fetchConversationSelect<T = any>(id: string) {
+  return post<T>({
+    url: '/api/conversation-select',
+    data: { id },
+  })
+}
+
+export function fetchConversationDelete<T = any>(id: string) {
+  return post<T>({
+    url: '/api/conversation-delete',
+    data: { id },
+  })
+}
+
+export function fetchExport<T = string>(id: string, format: 'markdown' | 'json' | 'html', leafId?: string) {
+  return post<T>({
+    url: '/api/export',
+    data: { id, format, leafId },
+  })
+}
+
+export function fetchImport<T = any>(conversations: any[], dryRun: boolean) {
+  return post<T>({
+    url: '/api/import',
+    data: { conversations, dryRun },
+  })
+}
+
 export function fetchSession<T>() {
   return post<T>({
     url: '/api/session',
   })
 }
 
+/** Models of the provider and the allowed ones, with context size, pricing and features */
+export function fetchModels<T = any>() {
+  return post<T>({
+    url: '/api/models',
+  })
+}
+
 export function fetchVerify<T>(token: string) {
   return post<T>({
     url: '/api/verify',